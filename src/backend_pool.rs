@@ -1,40 +1,185 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use arc_swap::ArcSwap;
 use pingora_load_balancing::Backend;
 
+use crate::config::SelectionMode;
+use crate::upstream::ProxyMetadata;
+use crate::username_template::SessionHints;
+
+/// Outcome of a selection that found candidates
+pub enum Selected {
+    Backend(Backend),
+    /// Every remaining candidate is rate limited; retry after this long
+    Limited(Duration),
+}
+
 /// Round-robin backend pool using atomic counter for thread-safe selection
+///
+/// The backend list can be swapped at runtime (source refresh) without locking readers.
 pub struct SimpleBackendPool {
-    backends: Vec<Backend>,
+    backends: ArcSwap<Vec<Backend>>,
     counter: AtomicUsize,
+    selection: SelectionMode,
+    /// Exit IP of the most recent selection with a known exit, for `DistinctExitIp`
+    last_exit: Mutex<Option<IpAddr>>,
 }
 
 impl SimpleBackendPool {
     /// Creates a new pool wrapped in Arc for shared access
-    pub fn new(backends: Vec<Backend>) -> Arc<Self> {
+    pub fn new(backends: Vec<Backend>, selection: SelectionMode) -> Arc<Self> {
         Arc::new(Self {
-            backends,
+            backends: ArcSwap::from_pointee(backends),
             counter: AtomicUsize::new(0),
+            selection,
+            last_exit: Mutex::new(None),
         })
     }
 
+    /// Replaces the backend list
+    pub fn update(&self, backends: Vec<Backend>) {
+        self.backends.store(Arc::new(backends));
+    }
+
     /// Returns the next backend using round-robin
-    pub fn select(&self) -> Option<&Backend> {
-        if self.backends.is_empty() {
+    ///
+    /// Only backends in the requested pool and serving the requested location are
+    /// considered, starting from the round-robin position. Backends for which
+    /// `exclude` returns true and backends at their rate limit are skipped; if only
+    /// rate-limited ones remain, returns how long until the first frees up.
+    pub fn select(
+        &self,
+        hints: &SessionHints,
+        exclude: impl Fn(&Backend) -> bool,
+    ) -> Option<Selected> {
+        let backends = self.backends.load();
+        if backends.is_empty() {
             return None;
         }
 
-        let index = self.counter.fetch_add(1, Ordering::Relaxed) % self.backends.len();
-        self.backends.get(index)
+        let start = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut candidates = (0..backends.len())
+            .map(|offset| &backends[(start + offset) % backends.len()])
+            .filter(|backend| matches_request(backend, hints))
+            .filter(|backend| !exclude(backend));
+
+        let mut limited = None;
+        let selected = match self.selection {
+            SelectionMode::RoundRobin => candidates.find(|backend| admit(backend, &mut limited)),
+            SelectionMode::DistinctExitIp => self.select_distinct(candidates, &mut limited),
+        };
+
+        match selected {
+            Some(backend) => Some(Selected::Backend(backend.clone())),
+            None => limited.map(Selected::Limited),
+        }
+    }
+
+    /// Returns a backend with the given ID if it still serves the request and is within
+    /// its rate limit, for sticky sessions
+    pub fn select_id(&self, id: &str, hints: &SessionHints) -> Option<Backend> {
+        let backends = self.backends.load();
+        backends
+            .iter()
+            .filter(|backend| {
+                backend
+                    .ext
+                    .get::<ProxyMetadata>()
+                    .is_some_and(|metadata| metadata.id == id)
+            })
+            .filter(|backend| matches_request(backend, hints))
+            .find(|backend| admit(backend, &mut None))
+            .cloned()
+    }
+
+    /// Picks the first candidate not exiting from the last known exit IP handed out
+    ///
+    /// This only separates back-to-back selections: exits of requests still in flight
+    /// are not tracked, so concurrent requests can share an exit IP once others were
+    /// selected in between. Backends not probed yet are always eligible and leave the
+    /// last known exit in place. When every candidate shares that exit IP, the first
+    /// admitted one is used rather than failing the request.
+    fn select_distinct<'a>(
+        &self,
+        candidates: impl Iterator<Item = &'a Backend>,
+        limited: &mut Option<Duration>,
+    ) -> Option<&'a Backend> {
+        let mut last_exit = self.last_exit.lock().unwrap_or_else(|e| e.into_inner());
+        let mut same_exit = Vec::new();
+
+        for backend in candidates {
+            let exit = exit_ip(backend);
+            if exit.is_some() && exit == *last_exit {
+                same_exit.push(backend);
+                continue;
+            }
+            if admit(backend, limited) {
+                if exit.is_some() {
+                    *last_exit = exit;
+                }
+                return Some(backend);
+            }
+        }
+
+        same_exit.into_iter().find(|backend| admit(backend, limited))
     }
 
-    /// Returns the number of backends
-    pub fn len(&self) -> usize {
-        self.backends.len()
+    /// Returns a snapshot of all backends
+    pub fn backends(&self) -> Arc<Vec<Backend>> {
+        self.backends.load_full()
     }
 
-    /// Returns true if no backends are configured
-    pub fn is_empty(&self) -> bool {
-        self.backends.is_empty()
+    /// Returns the number of distinct backend IDs in the requested pool able to serve the
+    /// requested location, not counting those for which `exclude` returns true
+    pub fn identities_matching(
+        &self,
+        hints: &SessionHints,
+        exclude: impl Fn(&ProxyMetadata) -> bool,
+    ) -> usize {
+        let backends = self.backends.load();
+        backends
+            .iter()
+            .filter_map(|backend| backend.ext.get::<ProxyMetadata>())
+            .filter(|metadata| hints.matches(metadata) && !exclude(metadata))
+            .map(|metadata| metadata.id.as_str())
+            .collect::<HashSet<_>>()
+            .len()
+    }
+}
+
+fn matches_request(backend: &Backend, hints: &SessionHints) -> bool {
+    backend
+        .ext
+        .get::<ProxyMetadata>()
+        .is_some_and(|metadata| hints.matches(metadata))
+}
+
+/// Takes a rate limit slot for the backend, recording the shortest wait when it is limited
+fn admit(backend: &Backend, limited: &mut Option<Duration>) -> bool {
+    let Some(limiter) = backend
+        .ext
+        .get::<ProxyMetadata>()
+        .and_then(|metadata| metadata.limiter.as_ref())
+    else {
+        return true;
+    };
+
+    match limiter.try_acquire() {
+        Ok(()) => true,
+        Err(wait) => {
+            *limited = Some(limited.map_or(wait, |shortest| shortest.min(wait)));
+            false
+        }
     }
 }
+
+fn exit_ip(backend: &Backend) -> Option<IpAddr> {
+    backend
+        .ext
+        .get::<ProxyMetadata>()
+        .and_then(|metadata| metadata.exit_ip.get())
+}
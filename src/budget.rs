@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+
+/// Token budget keeping extra work (hedges, retries) to a percentage of requests
+///
+/// Every request earns `percent / 100` of a token and every extra attempt spends a
+/// whole one. Savings are capped so an idle period cannot fund a burst.
+pub struct TokenBudget {
+    earn: f64,
+    tokens: Mutex<f64>,
+}
+
+impl TokenBudget {
+    pub const MAX_TOKENS: f64 = 10.0;
+
+    /// Creates a budget holding `initial` tokens (capped at `MAX_TOKENS`)
+    pub fn new(percent: f64, initial: f64) -> Self {
+        Self {
+            earn: percent / 100.0,
+            tokens: Mutex::new(initial.min(Self::MAX_TOKENS)),
+        }
+    }
+
+    /// Counts one request towards the budget
+    pub fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        *tokens = (*tokens + self.earn).min(Self::MAX_TOKENS);
+    }
+
+    /// Takes budget for one extra attempt, returning false when it is used up
+    pub fn try_withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+
+    /// Returns a token taken by `try_withdraw` for an attempt that never ran
+    pub fn refund(&self) {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        *tokens = (*tokens + 1.0).min(Self::MAX_TOKENS);
+    }
+}
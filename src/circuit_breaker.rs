@@ -0,0 +1,296 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tracing::info;
+
+use crate::config::CircuitBreakerConfig;
+
+/// Per-backend circuit breakers for connection errors and timeouts, keyed by backend ID
+///
+/// A closed circuit passes all traffic. It opens after `consecutive_failures` failures
+/// in a row or once the failure rate over the sliding window reaches `failure_rate`.
+/// An open circuit rejects traffic for `open_secs`, then turns half-open and lets a
+/// single trial request through: success closes it, failure opens it again.
+pub struct CircuitBreakers {
+    config: CircuitBreakerConfig,
+    circuits: DashMap<String, Circuit>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: u32,
+    /// Attempt times and whether they succeeded, inside the sliding window
+    outcomes: VecDeque<(Instant, bool)>,
+}
+
+#[derive(Default)]
+enum CircuitState {
+    #[default]
+    Closed,
+    Open {
+        until: Instant,
+    },
+    /// Start of the trial request in flight, if any
+    HalfOpen {
+        trial: Option<Instant>,
+    },
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            circuits: DashMap::new(),
+        }
+    }
+
+    /// Returns true if the backend cannot take a request right now: its circuit is
+    /// open, or half-open with a trial already in flight
+    pub fn is_blocked(&self, id: &str) -> bool {
+        let Some(circuit) = self.circuits.get(id) else {
+            return false;
+        };
+        let now = Instant::now();
+        match circuit.state {
+            CircuitState::Closed => false,
+            CircuitState::Open { until } => until > now,
+            CircuitState::HalfOpen { trial } => {
+                trial.is_some_and(|at| !self.trial_expired(at, now))
+            }
+        }
+    }
+
+    /// Claims passage for one request; for a half-open circuit this is the single trial
+    pub fn try_pass(&self, id: &str) -> bool {
+        let Some(mut circuit) = self.circuits.get_mut(id) else {
+            return true;
+        };
+        let now = Instant::now();
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open { until } if until > now => false,
+            CircuitState::HalfOpen { trial: Some(at) } if !self.trial_expired(at, now) => false,
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => {
+                circuit.state = CircuitState::HalfOpen { trial: Some(now) };
+                true
+            }
+        }
+    }
+
+    /// Gives back a trial claimed by `try_pass` that was never started
+    pub fn release(&self, id: &str) {
+        if let Some(mut circuit) = self.circuits.get_mut(id)
+            && matches!(circuit.state, CircuitState::HalfOpen { trial: Some(_) })
+        {
+            circuit.state = CircuitState::HalfOpen { trial: None };
+        }
+    }
+
+    pub fn record_success(&self, id: &str) {
+        let mut circuit = self.circuits.entry(id.to_string()).or_default();
+        if matches!(circuit.state, CircuitState::HalfOpen { .. }) {
+            info!("Circuit for {id} closed after a successful trial");
+            *circuit = Circuit::default();
+            return;
+        }
+
+        circuit.consecutive_failures = 0;
+        self.push_outcome(&mut circuit, true);
+    }
+
+    pub fn record_failure(&self, id: &str) {
+        let mut circuit = self.circuits.entry(id.to_string()).or_default();
+        let now = Instant::now();
+        if matches!(circuit.state, CircuitState::HalfOpen { .. }) {
+            info!("Circuit for {id} reopened after a failed trial");
+            circuit.state = CircuitState::Open {
+                until: now + self.open_duration(),
+            };
+            return;
+        }
+
+        circuit.consecutive_failures += 1;
+        self.push_outcome(&mut circuit, false);
+        if matches!(circuit.state, CircuitState::Closed) && self.should_trip(&circuit) {
+            info!(
+                "Circuit for {id} opened for {}s after {} consecutive failures ({} of {} recent attempts failed)",
+                self.config.open_secs,
+                circuit.consecutive_failures,
+                circuit.outcomes.iter().filter(|(_, ok)| !ok).count(),
+                circuit.outcomes.len()
+            );
+            circuit.state = CircuitState::Open {
+                until: now + self.open_duration(),
+            };
+            circuit.consecutive_failures = 0;
+            circuit.outcomes.clear();
+        }
+    }
+
+    fn should_trip(&self, circuit: &Circuit) -> bool {
+        let config = &self.config;
+        if config.consecutive_failures > 0
+            && circuit.consecutive_failures >= config.consecutive_failures
+        {
+            return true;
+        }
+
+        let attempts = circuit.outcomes.len();
+        if config.failure_rate <= 0.0 || attempts < config.min_requests.max(1) as usize {
+            return false;
+        }
+        let failures = circuit.outcomes.iter().filter(|(_, ok)| !ok).count();
+        failures as f64 / attempts as f64 >= config.failure_rate
+    }
+
+    /// Records an attempt and drops those that left the sliding window
+    fn push_outcome(&self, circuit: &mut Circuit, ok: bool) {
+        let now = Instant::now();
+        let window = self.config.window();
+        while circuit
+            .outcomes
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= window)
+        {
+            circuit.outcomes.pop_front();
+        }
+        circuit.outcomes.push_back((now, ok));
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_secs(self.config.open_secs)
+    }
+
+    /// A trial that never reported back (e.g. its connection was dropped) stops
+    /// blocking the backend after another open period
+    fn trial_expired(&self, started: Instant, now: Instant) -> bool {
+        now.duration_since(started) >= self.open_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers(
+        consecutive_failures: u32,
+        failure_rate: f64,
+        min_requests: u32,
+    ) -> CircuitBreakers {
+        CircuitBreakers::new(CircuitBreakerConfig {
+            consecutive_failures,
+            failure_rate,
+            window_secs: 60,
+            min_requests,
+            open_secs: 30,
+        })
+    }
+
+    /// Lets the open period of `id` run out without waiting for it
+    fn end_open_period(breakers: &CircuitBreakers, id: &str) {
+        let mut circuit = breakers.circuits.get_mut(id).unwrap();
+        assert!(matches!(circuit.state, CircuitState::Open { .. }));
+        circuit.state = CircuitState::Open {
+            until: Instant::now(),
+        };
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breakers = breakers(3, 0.0, 0);
+        breakers.record_failure("a");
+        breakers.record_failure("a");
+        breakers.record_success("a");
+        breakers.record_failure("a");
+        breakers.record_failure("a");
+        assert!(!breakers.is_blocked("a"));
+        assert!(breakers.try_pass("a"));
+
+        breakers.record_failure("a");
+        assert!(breakers.is_blocked("a"));
+        assert!(!breakers.try_pass("a"));
+        assert!(!breakers.is_blocked("b"));
+    }
+
+    #[test]
+    fn opens_on_failure_rate_after_min_requests() {
+        let breakers = breakers(0, 0.5, 4);
+        breakers.record_failure("a");
+        breakers.record_success("a");
+        breakers.record_failure("a");
+        assert!(!breakers.is_blocked("a"), "below min_requests");
+
+        breakers.record_success("a");
+        breakers.record_failure("a");
+        assert!(breakers.is_blocked("a"), "3 of 5 attempts failed");
+    }
+
+    #[test]
+    fn failure_rate_ignores_attempts_outside_the_window() {
+        let breakers = breakers(0, 0.5, 2);
+        breakers.record_failure("a");
+        breakers.circuits.get_mut("a").unwrap().outcomes[0].0 -= Duration::from_secs(120);
+
+        breakers.record_success("a");
+        breakers.record_success("a");
+        breakers.record_failure("a");
+        assert!(!breakers.is_blocked("a"), "1 of 3 recent attempts failed");
+    }
+
+    #[test]
+    fn half_open_admits_one_trial_and_closes_on_success() {
+        let breakers = breakers(1, 0.0, 0);
+        breakers.record_failure("a");
+        end_open_period(&breakers, "a");
+        assert!(!breakers.is_blocked("a"));
+
+        assert!(breakers.try_pass("a"));
+        assert!(breakers.is_blocked("a"), "trial in flight");
+        assert!(!breakers.try_pass("a"));
+
+        breakers.record_success("a");
+        assert!(!breakers.is_blocked("a"));
+        assert!(breakers.try_pass("a"));
+        assert!(breakers.try_pass("a"));
+    }
+
+    #[test]
+    fn failed_trial_reopens_the_circuit() {
+        let breakers = breakers(1, 0.0, 0);
+        breakers.record_failure("a");
+        end_open_period(&breakers, "a");
+        assert!(breakers.try_pass("a"));
+
+        breakers.record_failure("a");
+        assert!(breakers.is_blocked("a"));
+        assert!(!breakers.try_pass("a"));
+    }
+
+    #[test]
+    fn released_trial_can_be_claimed_again() {
+        let breakers = breakers(1, 0.0, 0);
+        breakers.record_failure("a");
+        end_open_period(&breakers, "a");
+        assert!(breakers.try_pass("a"));
+
+        breakers.release("a");
+        assert!(!breakers.is_blocked("a"));
+        assert!(breakers.try_pass("a"));
+    }
+
+    #[test]
+    fn abandoned_trial_expires_after_an_open_period() {
+        let breakers = breakers(1, 0.0, 0);
+        breakers.record_failure("a");
+        end_open_period(&breakers, "a");
+        assert!(breakers.try_pass("a"));
+
+        breakers.circuits.get_mut("a").unwrap().state = CircuitState::HalfOpen {
+            trial: Some(Instant::now() - Duration::from_secs(30)),
+        };
+        assert!(!breakers.is_blocked("a"));
+        assert!(breakers.try_pass("a"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use pingora_core::server::configuration::Opt;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::backend_pool::SimpleBackendPool;
+use crate::config::{Config, SourceLocation};
+use crate::geo::GeoDb;
+use crate::inbound_tls;
+use crate::proxy_handler::ForwardProxy;
+use crate::sources::{ProxySources, local_files};
+use crate::upstream::{ProxyMetadata, parse_proxy_list};
+
+/// Rotating forward proxy over multiple upstream proxy providers
+#[derive(Parser, Debug)]
+#[command(name = "proxywar", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the proxy server
+    Serve(ServeArgs),
+    /// Parse the config and proxy list, reporting every problem found
+    Validate(FileArgs),
+    /// Test each proxy once and print a status table
+    Check(CheckArgs),
+}
+
+/// Config and proxy list locations shared by all subcommands
+#[derive(Args, Debug)]
+pub struct FileArgs {
+    /// Service config file (optional; defaults apply when missing)
+    #[arg(long, default_value = "config/proxywar.yaml")]
+    pub config: PathBuf,
+    /// Proxy list file, replacing `proxies` and `sources` from the config
+    #[arg(long)]
+    pub proxies: Option<PathBuf>,
+}
+
+impl FileArgs {
+    /// Loads the config with command-line overrides applied
+    pub fn load_config(&self) -> Result<Config> {
+        let mut config = Config::from_file_or_default(&self.config)?;
+        if let Some(proxies) = &self.proxies {
+            config.proxies = proxies.clone();
+            config.sources.clear();
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    #[command(flatten)]
+    pub files: FileArgs,
+    /// Address for the plain TCP listener, overriding `listen` from the config
+    #[arg(long)]
+    pub listen: Option<String>,
+    #[command(flatten)]
+    pub server: Opt,
+}
+
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    #[command(flatten)]
+    pub files: FileArgs,
+    /// host:port to open a CONNECT tunnel to through each proxy
+    #[arg(long, default_value = "api.ipify.org:443")]
+    pub target: String,
+    /// Seconds to wait for each proxy
+    #[arg(long, default_value_t = 15)]
+    pub timeout: u64,
+    /// Number of proxies checked in parallel
+    #[arg(long, default_value_t = 16)]
+    pub concurrency: usize,
+}
+
+/// Reports every config and proxy list problem; returns false if any were found
+pub fn validate(args: &FileArgs) -> Result<bool> {
+    let mut ok = true;
+
+    let mut config = if args.config.exists() {
+        match Config::from_file(&args.config) {
+            Ok(config) => {
+                println!("config {}: ok", args.config.display());
+                config
+            }
+            Err(err) => {
+                println!("config {}: {err:#}", args.config.display());
+                ok = false;
+                Config::default()
+            }
+        }
+    } else {
+        println!("config {}: not found, using defaults", args.config.display());
+        Config::default()
+    };
+
+    if let Some(tls) = &config.tls
+        && let Err(err) = inbound_tls::tls_settings(tls)
+    {
+        println!("tls listener: {err:#}");
+        ok = false;
+    }
+
+    if let Some(database) = &config.geo.database
+        && let Err(err) = GeoDb::open(database)
+    {
+        println!("geo database: {err:#}");
+        ok = false;
+    }
+
+    if let Some(echo_url) = &config.exit_ip.echo_url
+        && !echo_url.starts_with("http://")
+    {
+        println!("exit_ip.echo_url: must be a plain http:// URL, got {echo_url}");
+        ok = false;
+    }
+
+    if let Some(proxies) = &args.proxies {
+        config.proxies = proxies.clone();
+        config.sources.clear();
+    }
+
+    let mut total = 0;
+    for source in config.proxy_sources() {
+        if let SourceLocation::Url(_) = source.location {
+            println!("source {}: remote, not fetched", source.location);
+            continue;
+        }
+
+        let files = match local_files(&source.location) {
+            Ok(files) => files,
+            Err(err) => {
+                println!("source {}: {err:#}", source.location);
+                ok = false;
+                continue;
+            }
+        };
+
+        for path in files {
+            let raw = match fs::read_to_string(&path) {
+                Ok(raw) => raw,
+                Err(err) => {
+                    println!("{}: {err}", path.display());
+                    ok = false;
+                    continue;
+                }
+            };
+            let (backends, errors) =
+                parse_proxy_list(&raw, source.format, source.decode_credentials);
+
+            for error in &errors {
+                println!(
+                    "{}:{}: {:#}\n    {}",
+                    path.display(),
+                    error.line,
+                    error.error,
+                    error.text
+                );
+            }
+            println!(
+                "proxies {}: {} valid, {} invalid",
+                path.display(),
+                backends.len(),
+                errors.len()
+            );
+
+            total += backends.len();
+            ok &= errors.is_empty();
+        }
+    }
+
+    if total == 0 {
+        println!("no usable proxies in local sources");
+        ok = false;
+    }
+
+    Ok(ok)
+}
+
+/// Probes every proxy once and prints a status table; returns false if any failed
+pub fn check(args: &CheckArgs) -> Result<bool> {
+    let config = args.files.load_config()?;
+
+    let runtime = tokio::runtime::Runtime::new().context("failed to start runtime")?;
+    runtime.block_on(async {
+        let pool = SimpleBackendPool::new(Vec::new(), config.selection);
+        let geo = GeoDb::from_config(&config.geo)?;
+        ProxySources::new(config.proxy_sources(), &config.dns, geo, pool.clone())?
+            .refresh_all()
+            .await?;
+        let backends = pool.backends();
+        let proxy = Arc::new(ForwardProxy::new(pool, &config));
+        let limit = Arc::new(Semaphore::new(args.concurrency.max(1)));
+        let probe_timeout = Duration::from_secs(args.timeout);
+        let mut probes = JoinSet::new();
+
+        for (idx, backend) in backends.iter().cloned().enumerate() {
+            let proxy = Arc::clone(&proxy);
+            let limit = Arc::clone(&limit);
+            let target = args.target.clone();
+            probes.spawn(async move {
+                let _permit = limit.acquire_owned().await;
+                let started = Instant::now();
+                let result = timeout(probe_timeout, proxy.probe_backend(&backend, &target))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+                // Hostname proxies expand to one backend per address; show which one
+                let label = match backend.ext.get::<ProxyMetadata>() {
+                    Some(m) if m.host.parse::<IpAddr>().is_ok() => m.label(),
+                    Some(m) => format!("{} ({})", m.label(), backend.addr),
+                    None => backend.addr.to_string(),
+                };
+                (idx, label, result, started.elapsed())
+            });
+        }
+
+        let mut rows = probes.join_all().await;
+        rows.sort_by_key(|(idx, ..)| *idx);
+
+        println!("{:<4} {:<64} {:<8} {:>9}  DETAIL", "#", "PROXY", "STATUS", "LATENCY");
+        let mut healthy = 0;
+        for (idx, label, result, elapsed) in &rows {
+            let (status, detail) = match result {
+                Ok(code) if (200..300).contains(code) => {
+                    healthy += 1;
+                    ("ok".to_string(), code.to_string())
+                }
+                Ok(code) => ("fail".to_string(), code.to_string()),
+                Err(err) => ("error".to_string(), format!("{err:#}")),
+            };
+            println!(
+                "{:<4} {:<64} {:<8} {:>7}ms  {}",
+                idx + 1,
+                label,
+                status,
+                elapsed.as_millis(),
+                detail
+            );
+        }
+        println!("{healthy}/{} proxies healthy", rows.len());
+
+        Ok::<_, anyhow::Error>(healthy == rows.len())
+    })
+}
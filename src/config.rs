@@ -0,0 +1,893 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::header_rules::HeaderRule;
+use crate::http_head;
+use crate::proxy_list::ListFormat;
+use crate::tunnel::TunnelPolicy;
+use crate::username_template::UsernameTemplate;
+
+/// Service configuration loaded from YAML
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address for the plain TCP listener
+    pub listen: String,
+    /// Proxy list file
+    pub proxies: PathBuf,
+    /// Proxy list layout; `auto` detects it from the content
+    pub proxy_format: ListFormat,
+    /// Proxy list sources; when empty, `proxies` is the only source
+    pub sources: Vec<SourceConfig>,
+    pub dns: DnsConfig,
+    pub geo: GeoConfig,
+    /// How the next backend is picked
+    pub selection: SelectionMode,
+    pub exit_ip: ExitIpConfig,
+    pub rate_limit: RateLimitConfig,
+    /// Named pools; sources join one with `pool`, clients pick one with `X-Proxywar-Pool`
+    pub pools: HashMap<String, PoolConfig>,
+    /// Bandwidth budgets per provider
+    pub budgets: Vec<BudgetConfig>,
+    pub sessions: SessionConfig,
+    pub state: StateConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub hedging: HedgeConfig,
+    pub timeouts: TimeoutConfig,
+    pub retries: RetryConfig,
+    /// Header rules for every request; pool rules run after these
+    pub header_rules: Vec<HeaderRule>,
+    pub shutdown: ShutdownConfig,
+    pub tunnel: TunnelConfig,
+    pub tls: Option<InboundTlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:8890".to_string(),
+            proxies: PathBuf::from("config/proxies.txt"),
+            proxy_format: ListFormat::default(),
+            sources: Vec::new(),
+            dns: DnsConfig::default(),
+            geo: GeoConfig::default(),
+            selection: SelectionMode::default(),
+            exit_ip: ExitIpConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            pools: HashMap::new(),
+            budgets: Vec::new(),
+            sessions: SessionConfig::default(),
+            state: StateConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            hedging: HedgeConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            retries: RetryConfig::default(),
+            header_rules: Vec::new(),
+            shutdown: ShutdownConfig::default(),
+            tunnel: TunnelConfig::default(),
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads configuration from a YAML file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let raw = fs::read_to_string(path.as_ref())
+            .with_context(|| format!("failed to read config from {}", path.as_ref().display()))?;
+        let config: Self = serde_yaml::from_str(&raw)
+            .with_context(|| format!("invalid config in {}", path.as_ref().display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid config in {}", path.as_ref().display()))?;
+        Ok(config)
+    }
+
+    /// Checks constraints serde cannot express
+    fn validate(&self) -> Result<()> {
+        let limits = self
+            .sources
+            .iter()
+            .filter_map(|source| source.rate_limit.as_ref())
+            .chain(&self.rate_limit.default);
+        for limits in limits {
+            if limits
+                .requests_per_sec
+                .is_some_and(|rate| !(rate > 0.0 && rate.is_finite()))
+            {
+                anyhow::bail!("rate_limit.requests_per_sec must be a positive number");
+            }
+        }
+
+        let rate = self.circuit_breaker.failure_rate;
+        if !(0.0..=1.0).contains(&rate) {
+            anyhow::bail!("circuit_breaker.failure_rate must be between 0 and 1");
+        }
+
+        let budgets = [
+            ("hedging", self.hedging.budget_percent),
+            ("retries", self.retries.budget_percent),
+        ];
+        for (section, budget) in budgets {
+            if !(budget >= 0.0 && budget.is_finite()) {
+                anyhow::bail!("{section}.budget_percent must be a non-negative number");
+            }
+        }
+
+        // Usage counters live in the state snapshot; without one every restart resets them
+        if !self.budgets.is_empty() && self.state.path.is_none() {
+            anyhow::bail!("budgets need state.path to keep usage counters across restarts");
+        }
+
+        let rules = self
+            .header_rules
+            .iter()
+            .chain(self.pools.values().flat_map(|pool| &pool.header_rules));
+        for name in rules.flat_map(HeaderRule::header_names) {
+            if !http_head::is_valid_name(name) {
+                anyhow::bail!("invalid header name {name:?} in header_rules");
+            }
+            // Changing framing or the target would desync proxywar from the upstream
+            if ["content-length", "transfer-encoding", "host"]
+                .iter()
+                .any(|framing| name.eq_ignore_ascii_case(framing))
+            {
+                anyhow::bail!("header_rules cannot change {name}");
+            }
+        }
+
+        for (name, pool) in &self.pools {
+            if let Some(fallback) = &pool.fallback
+                && fallback != DEFAULT_POOL
+                && !self.pools.contains_key(fallback)
+            {
+                anyhow::bail!("pool {name} falls back to unknown pool {fallback}");
+            }
+            if let Some(fallback) = &pool.fallback
+                && self.pool_mode(fallback) != pool.mode
+            {
+                anyhow::bail!("pool {name} falls back to pool {fallback} of another mode");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the mode of a pool; pools without a section hold upstream proxies
+    pub fn pool_mode(&self, pool: &str) -> PoolMode {
+        self.pools
+            .get(pool)
+            .map_or_else(PoolMode::default, |pool| pool.mode)
+    }
+
+    /// Returns the configured proxy sources, falling back to the single `proxies` file
+    ///
+    /// Sources without their own `rate_limit` get the default one.
+    pub fn proxy_sources(&self) -> Vec<SourceConfig> {
+        let mut sources = self.sources.clone();
+        if sources.is_empty() {
+            sources.push(SourceConfig {
+                location: SourceLocation::File(self.proxies.clone()),
+                format: self.proxy_format,
+                decode_credentials: false,
+                refresh_secs: 0,
+                default_scheme: None,
+                username: None,
+                password: None,
+                username_template: None,
+                provider: None,
+                tags: Vec::new(),
+                headers: HashMap::new(),
+                rate_limit: None,
+                pool: None,
+            });
+        }
+
+        for source in &mut sources {
+            if source.rate_limit.is_none() {
+                source.rate_limit = self.rate_limit.default;
+            }
+        }
+        sources
+    }
+
+    /// Loads configuration from file if it exists, otherwise returns defaults
+    pub fn from_file_or_default(path: impl AsRef<Path>) -> Result<Self> {
+        if path.as_ref().exists() {
+            Self::from_file(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+}
+
+/// Pool for sources and requests that do not name one
+pub const DEFAULT_POOL: &str = "default";
+
+/// Where a proxy source reads its list from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceLocation {
+    /// A single list file
+    File(PathBuf),
+    /// Every file matching a glob pattern
+    Glob(String),
+    /// Every non-hidden file in a directory
+    Dir(PathBuf),
+    /// An HTTP(S) endpoint returning a list
+    Url(String),
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::Glob(pattern) => write!(f, "glob {pattern}"),
+            Self::Dir(path) => write!(f, "dir {}", path.display()),
+            Self::Url(url) => write!(f, "url {url}"),
+        }
+    }
+}
+
+/// A proxy list source with its own refresh interval and defaults
+#[derive(Debug, Clone, Deserialize)]
+pub struct SourceConfig {
+    #[serde(flatten)]
+    pub location: SourceLocation,
+    /// List layout; `auto` detects it per file or response
+    #[serde(default)]
+    pub format: ListFormat,
+    /// Percent-decode usernames and passwords of entries (`%40` for `@`)
+    #[serde(default)]
+    pub decode_credentials: bool,
+    /// Seconds between reloads; 0 loads once at startup
+    #[serde(default)]
+    pub refresh_secs: u64,
+    /// Scheme for entries that do not name one (defaults to http)
+    pub default_scheme: Option<String>,
+    /// Proxy credentials for entries without their own
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Provider username layout, e.g. `customer-{username}[-cc-{country}][-sessid-{session}]`
+    pub username_template: Option<UsernameTemplate>,
+    /// Provider name for entries without their own
+    pub provider: Option<String>,
+    /// Tags added to every entry
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Extra request headers for `url` sources, e.g. an API token
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Limits for each proxy of this source, replacing `rate_limit.default`
+    pub rate_limit: Option<BackendLimits>,
+    /// Pool the proxies belong to; `default` when unset
+    pub pool: Option<String>,
+}
+
+impl SourceConfig {
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        (self.refresh_secs > 0).then_some(Duration::from_secs(self.refresh_secs))
+    }
+}
+
+/// Re-resolution of hostname-based proxies
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Lower bound for record TTLs, also the retry delay after a failed lookup
+    pub min_ttl_secs: u64,
+    /// Upper bound for record TTLs
+    pub max_ttl_secs: u64,
+}
+
+impl DnsConfig {
+    pub fn min_ttl(&self) -> Duration {
+        Duration::from_secs(self.min_ttl_secs)
+    }
+
+    pub fn max_ttl(&self) -> Duration {
+        Duration::from_secs(self.max_ttl_secs.max(self.min_ttl_secs))
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            min_ttl_secs: 30,
+            max_ttl_secs: 3600,
+        }
+    }
+}
+
+/// Country/city targeting requested by clients
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoConfig {
+    /// MaxMind City or Country database used to tag backends without list attributes
+    pub database: Option<PathBuf>,
+    /// What to do when no backend matches the requested location
+    pub on_no_match: NoMatchPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoMatchPolicy {
+    /// Respond 503 naming the unmatched location
+    #[default]
+    Reject,
+    /// Ignore the requested location and use any backend
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionMode {
+    /// Every backend in turn
+    #[default]
+    RoundRobin,
+    /// Round-robin, but never the last exit IP handed out (needs `exit_ip.echo_url`)
+    DistinctExitIp,
+}
+
+/// Exit IP discovery through an IP-echo endpoint
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExitIpConfig {
+    /// Plain `http://` URL answering with the caller's IP; probing is off when unset
+    pub echo_url: Option<String>,
+    /// Seconds before a backend is probed again
+    pub interval_secs: u64,
+    /// Seconds to wait for one probe
+    pub timeout_secs: u64,
+    /// Backends probed in parallel
+    pub concurrency: usize,
+}
+
+impl ExitIpConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl Default for ExitIpConfig {
+    fn default() -> Self {
+        Self {
+            echo_url: None,
+            interval_secs: 600,
+            timeout_secs: 15,
+            concurrency: 8,
+        }
+    }
+}
+
+/// Per-proxy request limits and how long requests queue when every proxy is limited
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limits for proxies whose source sets none
+    pub default: Option<BackendLimits>,
+    /// Milliseconds a request may wait for a limited proxy before getting 503
+    pub max_wait_ms: u64,
+}
+
+impl RateLimitConfig {
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.max_wait_ms)
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            default: None,
+            max_wait_ms: 2000,
+        }
+    }
+}
+
+/// Limits applied to each proxy; unset limits are not enforced
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendLimits {
+    /// Sustained requests per second (token bucket refill rate)
+    pub requests_per_sec: Option<f64>,
+    /// Requests allowed at once; defaults to one second's worth
+    pub burst: Option<u32>,
+    /// Requests allowed per rolling window
+    pub window_requests: Option<u32>,
+    /// Rolling window length in seconds
+    #[serde(default = "BackendLimits::default_window_secs")]
+    pub window_secs: u64,
+}
+
+impl BackendLimits {
+    fn default_window_secs() -> u64 {
+        60
+    }
+
+    pub fn burst(&self) -> f64 {
+        match (self.burst, self.requests_per_sec) {
+            (Some(burst), _) => f64::from(burst.max(1)),
+            (None, Some(rate)) => rate.ceil().max(1.0),
+            (None, None) => 1.0,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs.max(1))
+    }
+}
+
+/// Settings for one named pool
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// What the pool's backends are, deciding the form requests are sent in
+    pub mode: PoolMode,
+    /// Pool used instead when every backend of this one is out of budget
+    pub fallback: Option<String>,
+    /// Upstream timeouts replacing the `timeouts` defaults for this pool
+    pub timeouts: TimeoutOverrides,
+    /// Header rules for the pool's requests, after the global ones
+    pub header_rules: Vec<HeaderRule>,
+}
+
+/// Kind of backends in a pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolMode {
+    /// Upstream HTTP proxies: absolute-form requests with proxy credentials, and CONNECT
+    #[default]
+    Proxy,
+    /// Origin servers or reverse-proxy targets: origin-form requests, no CONNECT
+    Origin,
+}
+
+/// Bandwidth budget for one provider; its backends are skipped once a limit is reached
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BudgetConfig {
+    pub provider: String,
+    /// Bytes per UTC day, e.g. `5GB` or `500MiB`
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub daily: Option<u64>,
+    /// Bytes per UTC calendar month
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub monthly: Option<u64>,
+}
+
+/// Accepts a plain byte count or a string with a unit (`KB`, `MB`, `GB`, `TB` or `KiB`..`TiB`)
+fn deserialize_byte_size<'de, D>(deserializer: D) -> std::result::Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Bytes(u64),
+        Text(String),
+    }
+
+    let text = match Option::<Raw>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Raw::Bytes(bytes)) => return Ok(Some(bytes)),
+        Some(Raw::Text(text)) => text,
+    };
+
+    let trimmed = text.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| serde::de::Error::custom(format!("invalid size {text:?}")))?;
+    let multiplier: f64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "kb" => 1e3,
+        "mb" => 1e6,
+        "gb" => 1e9,
+        "tb" => 1e12,
+        "kib" => 1024.0,
+        "mib" => 1024.0 * 1024.0,
+        "gib" => 1024.0 * 1024.0 * 1024.0,
+        "tib" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        other => {
+            return Err(serde::de::Error::custom(format!(
+                "unknown size unit {other:?} in {text:?}"
+            )));
+        }
+    };
+    Ok(Some((number * multiplier) as u64))
+}
+
+/// Sticky sessions: requests with the same `X-Proxywar-Session` reuse one backend
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Seconds a session stays pinned when the client sends no lifetime
+    pub ttl_secs: u64,
+}
+
+impl SessionConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self { ttl_secs: 1800 }
+    }
+}
+
+/// Snapshots of bans, backend stats and sticky sessions kept across restarts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /// JSON snapshot file; state is not kept across restarts when unset (the default)
+    pub path: Option<PathBuf>,
+    /// Seconds between snapshots (one is also taken on shutdown)
+    pub snapshot_secs: u64,
+    /// Snapshots older than this are ignored on start
+    pub max_age_secs: u64,
+    /// Bans older than this are dropped on start, giving proxies another chance
+    pub ban_max_age_secs: u64,
+}
+
+impl StateConfig {
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_secs(self.snapshot_secs.max(1))
+    }
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            snapshot_secs: 60,
+            max_age_secs: 3600,
+            ban_max_age_secs: 86_400,
+        }
+    }
+}
+
+/// Per-backend circuit breaker for connection errors and timeouts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Failures in a row that open the circuit; 0 disables this trigger
+    pub consecutive_failures: u32,
+    /// Share of failed attempts in the window that opens the circuit; 0 disables this trigger
+    pub failure_rate: f64,
+    /// Sliding window for `failure_rate`, in seconds
+    pub window_secs: u64,
+    /// Attempts needed in the window before `failure_rate` applies
+    pub min_requests: u32,
+    /// Seconds an open circuit rejects traffic before letting one trial request through
+    pub open_secs: u64,
+}
+
+impl CircuitBreakerConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs.max(1))
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            failure_rate: 0.5,
+            window_secs: 60,
+            min_requests: 10,
+            open_secs: 30,
+        }
+    }
+}
+
+/// Hedged CONNECT attempts: a second backend is tried when the first answers slowly
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HedgeConfig {
+    /// Milliseconds without a response header before hedging; 0 disables hedging
+    pub delay_ms: u64,
+    /// Hedges allowed, as a percentage of CONNECT requests
+    pub budget_percent: f64,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        Self {
+            delay_ms: 0,
+            budget_percent: 10.0,
+        }
+    }
+}
+
+/// Limits on trying further backends after a failed attempt
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Milliseconds a request may spend on all its attempts together
+    pub deadline_ms: u64,
+    /// Attempts per request, the first one and hedges included
+    pub max_attempts: u32,
+    /// Retries allowed across all requests, as a percentage of requests
+    pub budget_percent: f64,
+    /// Largest body sent with `Expect: 100-continue` that proxywar reads itself and
+    /// keeps for retries; larger and chunked bodies go straight to one upstream
+    pub buffer_body_bytes: u64,
+    /// Names the proxy of each attempt in error responses; off by default so clients
+    /// never learn upstream addresses or usernames
+    pub expose_proxies: bool,
+}
+
+impl RetryConfig {
+    pub fn deadline(&self) -> Duration {
+        Duration::from_millis(self.deadline_ms.max(1))
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            deadline_ms: 60_000,
+            max_attempts: 5,
+            budget_percent: 20.0,
+            buffer_body_bytes: 64 * 1024,
+            expose_proxies: false,
+        }
+    }
+}
+
+/// Client and upstream timeouts in milliseconds
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Reading the client's request header
+    pub client_header_ms: u64,
+    /// TCP connect to the proxy
+    pub connect_ms: u64,
+    /// TLS with the proxy
+    pub handshake_ms: u64,
+    /// The proxy's reply to CONNECT
+    pub connect_reply_ms: u64,
+    /// Response header of forwarded requests, or the first byte from the target once a
+    /// CONNECT tunnel is open
+    pub first_byte_ms: u64,
+    /// Upper bounds for timeouts requested with `X-Proxywar-*-Timeout` headers
+    pub max_connect_ms: u64,
+    pub max_handshake_ms: u64,
+    pub max_connect_reply_ms: u64,
+    pub max_first_byte_ms: u64,
+    /// Wait for an upstream `100 Continue` before the client's body is let through anyway
+    pub continue_ms: u64,
+}
+
+impl TimeoutConfig {
+    pub fn client_header(&self) -> Duration {
+        Duration::from_millis(self.client_header_ms.max(1))
+    }
+
+    pub fn continue_wait(&self) -> Duration {
+        Duration::from_millis(self.continue_ms.max(1))
+    }
+
+    /// Timeouts for one request: the client's requested values, then the pool's, then
+    /// the defaults; requested and pool values are capped at the maximums
+    pub fn upstream(
+        &self,
+        pool: Option<&TimeoutOverrides>,
+        requested: &TimeoutOverrides,
+    ) -> UpstreamTimeouts {
+        let pick = |requested: Option<u64>, max: u64, pool: Option<u64>, default: u64| {
+            let ms = requested.or(pool).map_or(default, |ms| ms.min(max));
+            Duration::from_millis(ms.max(1))
+        };
+        let pool = pool.copied().unwrap_or_default();
+
+        UpstreamTimeouts {
+            connect: pick(
+                requested.connect_ms,
+                self.max_connect_ms,
+                pool.connect_ms,
+                self.connect_ms,
+            ),
+            handshake: pick(
+                requested.handshake_ms,
+                self.max_handshake_ms,
+                pool.handshake_ms,
+                self.handshake_ms,
+            ),
+            connect_reply: pick(
+                requested.connect_reply_ms,
+                self.max_connect_reply_ms,
+                pool.connect_reply_ms,
+                self.connect_reply_ms,
+            ),
+            first_byte: pick(
+                requested.first_byte_ms,
+                self.max_first_byte_ms,
+                pool.first_byte_ms,
+                self.first_byte_ms,
+            ),
+        }
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            client_header_ms: 30_000,
+            connect_ms: 10_000,
+            handshake_ms: 30_000,
+            connect_reply_ms: 30_000,
+            first_byte_ms: 30_000,
+            max_connect_ms: 30_000,
+            max_handshake_ms: 60_000,
+            max_connect_reply_ms: 60_000,
+            max_first_byte_ms: 120_000,
+            continue_ms: 1_000,
+        }
+    }
+}
+
+/// Upstream timeouts set by a pool or requested by a client, in milliseconds
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutOverrides {
+    pub connect_ms: Option<u64>,
+    pub handshake_ms: Option<u64>,
+    pub connect_reply_ms: Option<u64>,
+    pub first_byte_ms: Option<u64>,
+}
+
+/// Upstream timeouts in effect for one request
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamTimeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    pub connect_reply: Duration,
+    pub first_byte: Duration,
+}
+
+/// Graceful shutdown behaviour for in-flight tunnels
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds active tunnels may keep running after shutdown starts
+    pub grace_period_secs: u64,
+}
+
+impl ShutdownConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_secs)
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            grace_period_secs: 30,
+        }
+    }
+}
+
+/// Timeouts for established tunnels; 0 disables a timeout
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// Seconds without bytes in either direction before closing
+    pub idle_timeout_secs: u64,
+    /// Maximum seconds a tunnel may stay open; no cap by default
+    pub max_lifetime_secs: u64,
+    /// Seconds one direction may stay open after the other finished
+    pub half_close_timeout_secs: u64,
+}
+
+impl TunnelConfig {
+    pub fn policy(&self) -> TunnelPolicy {
+        let secs = |s: u64| (s > 0).then_some(Duration::from_secs(s));
+        TunnelPolicy {
+            idle_timeout: secs(self.idle_timeout_secs),
+            max_lifetime: secs(self.max_lifetime_secs),
+            half_close_timeout: secs(self.half_close_timeout_secs),
+            first_byte_timeout: None,
+            upload_limit: None,
+        }
+    }
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 300,
+            max_lifetime_secs: 0,
+            half_close_timeout_secs: 30,
+        }
+    }
+}
+
+/// TLS-terminating listener so clients can reach proxywar over `https://`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InboundTlsConfig {
+    /// Address for the TLS listener
+    #[serde(default = "InboundTlsConfig::default_listen")]
+    pub listen: String,
+    /// PEM certificate (chain) served to clients
+    pub cert: PathBuf,
+    /// PEM private key for `cert`
+    pub key: PathBuf,
+    /// Seconds between checks for rotated certificate files
+    #[serde(default = "InboundTlsConfig::default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+    /// CA bundle for client certificates; enables mTLS when set
+    pub client_ca: Option<PathBuf>,
+    /// Client certificate common name to user identity; unmapped names are rejected when non-empty
+    #[serde(default)]
+    pub identities: HashMap<String, String>,
+}
+
+impl InboundTlsConfig {
+    fn default_listen() -> String {
+        "0.0.0.0:8891".to_string()
+    }
+
+    fn default_reload_interval_secs() -> u64 {
+        60
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Config> {
+        let config: Config = serde_yaml::from_str(raw)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn header_rules_cannot_touch_framing_headers() {
+        for rule in [
+            "remove: [Content-Length]",
+            "set: {transfer-encoding: chunked}",
+            "rename: {X-Host: Host}",
+            "add: {HOST: example.com}",
+        ] {
+            let raw = format!("header_rules:\n  - {rule}\n");
+            assert!(parse(&raw).is_err(), "accepted {rule}");
+        }
+        let pool = "pools:\n  a:\n    header_rules:\n      - remove: [host]\n";
+        assert!(parse(pool).is_err());
+
+        assert!(parse("header_rules:\n  - remove: [Via]\n").is_ok());
+    }
+
+    #[test]
+    fn budgets_need_a_state_file() {
+        let budgets = "budgets:\n  - provider: acme\n    daily: 5GB\n";
+        let err = parse(budgets).unwrap_err();
+        assert!(err.to_string().contains("state.path"), "{err}");
+
+        let config = parse(&format!("{budgets}state:\n  path: state/proxywar.json\n")).unwrap();
+        assert_eq!(config.budgets[0].daily, Some(5_000_000_000));
+    }
+}
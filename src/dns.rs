@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hickory_resolver::TokioAsyncResolver;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::config::DnsConfig;
+
+/// Resolves proxy hostnames to all their A/AAAA records and re-resolves them when their TTL expires
+pub struct DnsCache {
+    resolver: TokioAsyncResolver,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    entries: Mutex<HashMap<String, Resolved>>,
+}
+
+struct Resolved {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl DnsCache {
+    /// Creates a cache using the system resolver configuration
+    pub fn new(config: &DnsConfig) -> Result<Self> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .context("failed to read system DNS configuration")?;
+        Ok(Self {
+            resolver,
+            min_ttl: config.min_ttl(),
+            max_ttl: config.max_ttl(),
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Resolves hostnames that are new or expired; returns true if any address set changed
+    ///
+    /// A failed lookup keeps the previous addresses and is retried after the minimum TTL.
+    pub async fn refresh<'a>(&self, hosts: impl IntoIterator<Item = &'a str>) -> bool {
+        let now = Instant::now();
+        let due: Vec<String> = {
+            let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            hosts
+                .into_iter()
+                .filter(|host| host.parse::<IpAddr>().is_err())
+                .filter(|host| entries.get(*host).is_none_or(|r| r.expires <= now))
+                .map(str::to_string)
+                .collect()
+        };
+
+        let mut changed = false;
+        for host in due {
+            let lookup = self.resolver.lookup_ip(host.as_str()).await;
+
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            match lookup {
+                Ok(lookup) => {
+                    let mut addrs: Vec<IpAddr> = lookup.iter().collect();
+                    addrs.sort();
+                    addrs.dedup();
+
+                    let ttl = lookup
+                        .valid_until()
+                        .saturating_duration_since(std::time::Instant::now())
+                        .clamp(self.min_ttl, self.max_ttl);
+                    debug!("Resolved {host} to {addrs:?} (ttl {ttl:?})");
+
+                    if addrs.is_empty() {
+                        warn!("DNS lookup for {host} returned no addresses; keeping previous");
+                        Self::retry_later(&mut entries, &host, self.min_ttl);
+                        continue;
+                    }
+
+                    let previous = entries.insert(
+                        host,
+                        Resolved {
+                            addrs: addrs.clone(),
+                            expires: Instant::now() + ttl,
+                        },
+                    );
+                    changed |= previous.is_none_or(|p| p.addrs != addrs);
+                }
+                Err(err) => {
+                    warn!("DNS lookup for {host} failed; keeping previous addresses: {err}");
+                    Self::retry_later(&mut entries, &host, self.min_ttl);
+                }
+            }
+        }
+
+        changed
+    }
+
+    fn retry_later(entries: &mut HashMap<String, Resolved>, host: &str, delay: Duration) {
+        let expires = Instant::now() + delay;
+        entries
+            .entry(host.to_string())
+            .and_modify(|r| r.expires = expires)
+            .or_insert(Resolved {
+                addrs: Vec::new(),
+                expires,
+            });
+    }
+
+    /// Returns the addresses for a host: itself if it is an IP literal, otherwise the cached records
+    pub fn addresses(&self, host: &str) -> Vec<IpAddr> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return vec![ip];
+        }
+
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(host)
+            .map(|r| r.addrs.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns when the next cached record expires
+    pub fn next_expiry(&self) -> Option<Instant> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.values().map(|r| r.expires).min()
+    }
+
+    /// Drops hosts that are no longer referenced by any proxy
+    pub fn retain<'a>(&self, hosts: impl IntoIterator<Item = &'a str>) {
+        let keep: std::collections::HashSet<&str> = hosts.into_iter().collect();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|host, _| keep.contains(host.as_str()));
+    }
+}
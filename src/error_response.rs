@@ -0,0 +1,230 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// Why proxywar answered a request itself instead of relaying an upstream response
+///
+/// Sent as `X-Proxywar-Error` so clients can tell permanent failures from retryable ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorReason {
+    /// The client request could not be parsed or carried an invalid control header
+    BadRequest,
+    /// The client's certificate identity is not allowed
+    ClientForbidden,
+    /// Every attempted proxy rejected our credentials
+    UpstreamAuthFailed,
+    /// Proxies failed with connection or protocol errors
+    UpstreamError,
+    /// No proxies are configured for the request's pool
+    NoProxies,
+    /// No proxy serves the requested country or city
+    NoLocationMatch,
+    /// Every matching proxy is at its rate limit
+    RateLimited,
+    /// Every matching proxy is banned, behind an open circuit or over budget
+    NoHealthyProxies,
+    /// Retries are suspended because too many requests are failing
+    RetryBudgetExhausted,
+    /// The last attempted proxy did not answer in time
+    UpstreamTimeout,
+    /// The request's deadline passed before an attempt succeeded
+    DeadlineExceeded,
+    /// The proxy refused a CONNECT, for itself or the target; its response is relayed
+    TargetRejected,
+}
+
+impl ErrorReason {
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::BadRequest => 400,
+            Self::ClientForbidden => 403,
+            // Our upstream credentials failed, not the client's; 407 would ask the client for its own
+            Self::UpstreamAuthFailed | Self::UpstreamError | Self::TargetRejected => 502,
+            Self::NoProxies
+            | Self::NoLocationMatch
+            | Self::RateLimited
+            | Self::NoHealthyProxies
+            | Self::RetryBudgetExhausted => 503,
+            Self::UpstreamTimeout | Self::DeadlineExceeded => 504,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest => "bad_request",
+            Self::ClientForbidden => "client_forbidden",
+            Self::UpstreamAuthFailed => "upstream_auth_failed",
+            Self::UpstreamError => "upstream_error",
+            Self::NoProxies => "no_proxies",
+            Self::NoLocationMatch => "no_location_match",
+            Self::RateLimited => "rate_limited",
+            Self::NoHealthyProxies => "no_healthy_proxies",
+            Self::RetryBudgetExhausted => "retry_budget_exhausted",
+            Self::UpstreamTimeout => "upstream_timeout",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::TargetRejected => "target_rejected",
+        }
+    }
+
+    /// Default message for the response body
+    pub fn summary(&self) -> &'static str {
+        match self {
+            Self::BadRequest => "Malformed request",
+            Self::ClientForbidden => "Client not allowed",
+            Self::UpstreamAuthFailed => "Upstream proxies rejected their credentials",
+            Self::UpstreamError => "Upstream proxies failed",
+            Self::NoProxies => "No proxies configured",
+            Self::NoLocationMatch => "No proxy available for the requested location",
+            Self::RateLimited => "All proxies are rate limited",
+            Self::NoHealthyProxies => "No healthy proxies available",
+            Self::RetryBudgetExhausted => "Retry budget exhausted",
+            Self::UpstreamTimeout => "Upstream proxy timed out",
+            Self::DeadlineExceeded => "Request deadline exceeded",
+            Self::TargetRejected => "Target rejected the connection",
+        }
+    }
+
+    /// Returns false when sending the same request again cannot succeed without a
+    /// change to the request or the configuration
+    pub fn retryable(&self) -> bool {
+        !matches!(
+            self,
+            Self::BadRequest
+                | Self::ClientForbidden
+                | Self::UpstreamAuthFailed
+                | Self::NoProxies
+                | Self::NoLocationMatch
+        )
+    }
+
+    fn status_text(&self) -> &'static str {
+        match self.status() {
+            400 => "Bad Request",
+            403 => "Forbidden",
+            502 => "Bad Gateway",
+            504 => "Gateway Timeout",
+            _ => "Service Unavailable",
+        }
+    }
+}
+
+/// One finished attempt of a request, reported in error responses
+#[derive(Debug, Clone, Serialize)]
+pub struct AttemptRecord {
+    /// Address of the proxy, when `retries.expose_proxies` is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Status code the proxy answered with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// `timeout`, `protocol_error` or `error` when no usable response arrived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+    pub duration_ms: u64,
+}
+
+impl AttemptRecord {
+    /// Returns true if the proxy rejected our credentials
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(self.status, Some(407 | 402 | 511))
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        self.error == Some("timeout")
+    }
+}
+
+impl fmt::Display for AttemptRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(proxy) = &self.proxy {
+            write!(f, "{proxy} ")?;
+        }
+        match (self.status, self.error) {
+            (Some(status), _) => write!(f, "{status} {}ms", self.duration_ms),
+            (None, error) => write!(f, "{} {}ms", error.unwrap_or("error"), self.duration_ms),
+        }
+    }
+}
+
+/// Response proxywar sends when it cannot relay one from upstream
+pub struct ErrorResponse {
+    reason: ErrorReason,
+    message: String,
+    headers: Vec<(&'static str, String)>,
+    attempts: Vec<AttemptRecord>,
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct JsonBody<'a> {
+    error: &'static str,
+    message: &'a str,
+    retryable: bool,
+    attempts: &'a [AttemptRecord],
+}
+
+impl ErrorResponse {
+    pub fn new(reason: ErrorReason, message: impl Into<String>) -> Self {
+        Self {
+            reason,
+            message: message.into(),
+            headers: Vec::new(),
+            attempts: Vec::new(),
+            json: false,
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Lists the attempts in `X-Proxywar-Attempts` and the JSON body
+    pub fn with_attempts(mut self, attempts: Vec<AttemptRecord>) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Sends a JSON body instead of plain text, for clients that accept `application/json`
+    pub fn with_json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (content_type, body) = if self.json {
+            let body = JsonBody {
+                error: self.reason.code(),
+                message: &self.message,
+                retryable: self.reason.retryable(),
+                attempts: &self.attempts,
+            };
+            (
+                "application/json",
+                serde_json::to_string(&body).unwrap_or_default(),
+            )
+        } else {
+            ("text/plain", self.message.clone())
+        };
+
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: {content_type}\r\nX-Proxywar-Error: {}\r\n",
+            self.reason.status(),
+            self.reason.status_text(),
+            body.len(),
+            self.reason.code()
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.attempts.is_empty() {
+            let attempts: Vec<String> = self.attempts.iter().map(ToString::to_string).collect();
+            head.push_str(&format!("X-Proxywar-Attempts: {}\r\n", attempts.join(", ")));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+
+        let mut response = head.into_bytes();
+        response.extend_from_slice(body.as_bytes());
+        response
+    }
+}
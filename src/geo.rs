@@ -0,0 +1,52 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use maxminddb::{Reader, geoip2};
+
+use crate::config::GeoConfig;
+
+/// Local MaxMind (GeoIP2/GeoLite2 City or Country) database for tagging backends
+pub struct GeoDb {
+    reader: Reader<Vec<u8>>,
+}
+
+/// Location of one address; the country is a lowercase ISO code like list attributes
+#[derive(Debug, Clone, Default)]
+pub struct GeoLocation {
+    pub country: Option<String>,
+    pub city: Option<String>,
+}
+
+impl GeoDb {
+    /// Opens the configured database, if any, for sharing between sources and probes
+    pub fn from_config(config: &GeoConfig) -> Result<Option<Arc<Self>>> {
+        config
+            .database
+            .as_deref()
+            .map(|path| Self::open(path).map(Arc::new))
+            .transpose()
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = Reader::open_readfile(path)
+            .with_context(|| format!("failed to open geo database {}", path.display()))?;
+        Ok(Self { reader })
+    }
+
+    /// Looks up an address; returns None when the database has no record for it
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoLocation> {
+        let record: geoip2::City = self.reader.lookup(ip).ok()?;
+        Some(GeoLocation {
+            country: record
+                .country
+                .and_then(|c| c.iso_code)
+                .map(str::to_ascii_lowercase),
+            city: record
+                .city
+                .and_then(|c| c.names)
+                .and_then(|names| names.get("en").map(|name| name.to_string())),
+        })
+    }
+}
@@ -0,0 +1,301 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::http_head::Headers;
+use crate::username_template::SessionHints;
+
+/// Declarative header changes for requests on their way upstream or responses on
+/// their way to the client
+///
+/// Within a rule, headers are removed first, then renamed, set and added, each in the
+/// order the rule lists them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule {
+    /// Target hosts the rule applies to, exact or `*.example.com`; every host when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Whether the rule changes requests or responses
+    #[serde(default)]
+    pub on: RuleTarget,
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Old name to new name; values are kept
+    #[serde(default, deserialize_with = "deserialize_ordered")]
+    pub rename: Vec<(String, String)>,
+    /// Replaces every existing value of the header
+    #[serde(default, deserialize_with = "deserialize_ordered")]
+    pub set: Vec<(String, ValueTemplate)>,
+    /// Adds a value next to existing ones
+    #[serde(default, deserialize_with = "deserialize_ordered")]
+    pub add: Vec<(String, ValueTemplate)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleTarget {
+    #[default]
+    Request,
+    Response,
+}
+
+/// Values available to header templates for one request
+pub struct RuleContext<'a> {
+    pub client_ip: Option<IpAddr>,
+    /// Client identity from its certificate
+    pub user: Option<&'a str>,
+    pub hints: &'a SessionHints,
+    /// Target host without port
+    pub host: &'a str,
+}
+
+impl HeaderRule {
+    /// Returns every header name the rule mentions, for validation
+    pub fn header_names(&self) -> impl Iterator<Item = &str> {
+        self.remove
+            .iter()
+            .chain(self.rename.iter().flat_map(|(from, to)| [from, to]))
+            .chain(self.set.iter().map(|(name, _)| name))
+            .chain(self.add.iter().map(|(name, _)| name))
+            .map(String::as_str)
+    }
+
+    fn matches(&self, target: RuleTarget, host: &str) -> bool {
+        self.on == target
+            && (self.hosts.is_empty()
+                || self.hosts.iter().any(|pattern| host_matches(pattern, host)))
+    }
+
+    fn apply(&self, headers: &mut Headers, context: &RuleContext<'_>) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (from, to) in &self.rename {
+            headers.rename(from, to);
+        }
+        for (name, template) in &self.set {
+            if let Some(value) = template.expand(context) {
+                headers.remove(name);
+                headers.append(name, value);
+            }
+        }
+        for (name, template) in &self.add {
+            if let Some(value) = template.expand(context) {
+                headers.append(name, value);
+            }
+        }
+    }
+}
+
+/// Applies the rules for `target` that match the context's host, in order
+pub fn apply<'a>(
+    rules: impl IntoIterator<Item = &'a HeaderRule>,
+    target: RuleTarget,
+    headers: &mut Headers,
+    context: &RuleContext<'_>,
+) {
+    for rule in rules {
+        if rule.matches(target, context.host) {
+            rule.apply(headers, context);
+        }
+    }
+}
+
+/// Reads a map of header names into pairs, keeping the order they were written in
+fn deserialize_ordered<'de, D, V>(
+    deserializer: D,
+) -> std::result::Result<Vec<(String, V)>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    struct Ordered<V>(PhantomData<V>);
+
+    impl<'de, V: Deserialize<'de>> Visitor<'de> for Ordered<V> {
+        type Value = Vec<(String, V)>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a map of header names")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(
+            self,
+            mut map: A,
+        ) -> std::result::Result<Self::Value, A::Error> {
+            let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            Ok(entries)
+        }
+    }
+
+    deserializer.deserialize_map(Ordered(PhantomData))
+}
+
+/// Returns true if `host` equals `pattern`, or is a subdomain for `*.domain` patterns
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len().checked_sub(domain.len() + 1).is_some_and(|dot| {
+            let host = host.as_bytes();
+            host[dot] == b'.' && host[dot + 1..].eq_ignore_ascii_case(domain.as_bytes())
+        }),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Header value with `${variable}` placeholders
+///
+/// Variables are `client_ip`, `user`, `session`, `pool`, `country`, `city` and `host`.
+/// A header whose value uses a variable without a value is left out.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ValueTemplate {
+    parts: Arc<[Part]>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    ClientIp,
+    User,
+    Session,
+    Pool,
+    Country,
+    City,
+    Host,
+}
+
+impl Variable {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "client_ip" => Self::ClientIp,
+            "user" => Self::User,
+            "session" => Self::Session,
+            "pool" => Self::Pool,
+            "country" => Self::Country,
+            "city" => Self::City,
+            "host" => Self::Host,
+            other => bail!("unknown variable ${{{other}}}"),
+        })
+    }
+
+    fn value(self, context: &RuleContext<'_>) -> Option<String> {
+        match self {
+            Self::ClientIp => context.client_ip.map(|ip| ip.to_string()),
+            Self::User => context.user.map(str::to_string),
+            Self::Session => context.hints.session.clone(),
+            Self::Pool => Some(context.hints.pool().to_string()),
+            Self::Country => context.hints.country.clone(),
+            Self::City => context.hints.city.clone(),
+            Self::Host => Some(context.host.to_string()),
+        }
+    }
+}
+
+impl ValueTemplate {
+    fn expand(&self, context: &RuleContext<'_>) -> Option<String> {
+        let mut value = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(text) => value.push_str(text),
+                Part::Variable(variable) => value.push_str(&variable.value(context)?),
+            }
+        }
+        // Client-supplied values must not break the header block
+        (!value.chars().any(|c| c.is_ascii_control() && c != '\t')).then_some(value)
+    }
+}
+
+impl TryFrom<String> for ValueTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        if source.chars().any(|c| c.is_ascii_control() && c != '\t') {
+            bail!("header value {source:?} contains control characters");
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = source.as_str();
+        while let Some(start) = rest.find("${") {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                bail!("unclosed variable in header value {source:?}");
+            };
+            let name = &rest[start + 2..start + end];
+            parts.push(Part::Variable(Variable::parse(name)?));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Self {
+            parts: parts.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(hints: &SessionHints) -> RuleContext<'_> {
+        RuleContext {
+            client_ip: None,
+            user: Some("alice"),
+            hints,
+            host: "api.example.com",
+        }
+    }
+
+    #[test]
+    fn applies_entries_in_written_order() {
+        let rule: HeaderRule = serde_yaml::from_str(
+            "rename: {X-A: X-B, X-B: X-C}\nadd: {X-Z: '1', X-Y: '2', X-X: '${user}'}\n",
+        )
+        .unwrap();
+        let mut headers = Headers::default();
+        headers.append("X-A", "a");
+
+        let hints = SessionHints::default();
+        apply([&rule], RuleTarget::Request, &mut headers, &context(&hints));
+        let names: Vec<&str> = headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["X-C", "X-Z", "X-Y", "X-X"]);
+        assert_eq!(headers.get("x-x"), Some(&b"alice"[..]));
+    }
+
+    #[test]
+    fn set_skips_headers_with_missing_variables() {
+        let rule: HeaderRule =
+            serde_yaml::from_str("set: {X-Session: '${session}', X-Host: '${host}'}").unwrap();
+        let mut headers = Headers::default();
+        headers.append("X-Session", "old");
+
+        let hints = SessionHints::default();
+        apply([&rule], RuleTarget::Request, &mut headers, &context(&hints));
+        assert_eq!(headers.get("x-session"), Some(&b"old"[..]));
+        assert_eq!(headers.get("x-host"), Some(&b"api.example.com"[..]));
+    }
+
+    #[test]
+    fn matches_hosts_and_subdomains() {
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("API.example.com", "api.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+}
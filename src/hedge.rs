@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use crate::budget::TokenBudget;
+use crate::config::HedgeConfig;
+
+/// When and how often a slow CONNECT attempt may be hedged with a second backend
+pub struct Hedging {
+    delay: Duration,
+    budget: TokenBudget,
+}
+
+impl Hedging {
+    /// Returns None when hedging is disabled
+    pub fn new(config: &HedgeConfig) -> Option<Self> {
+        (config.delay_ms > 0).then(|| Self {
+            delay: Duration::from_millis(config.delay_ms),
+            // Starts empty: hedges are earned by the requests before them
+            budget: TokenBudget::new(config.budget_percent, 0.0),
+        })
+    }
+
+    /// Time without a response header before a hedge is considered
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Counts one hedgeable request towards the budget
+    pub fn deposit(&self) {
+        self.budget.deposit();
+    }
+
+    /// Takes budget for one hedge, returning false when it is used up
+    pub fn try_hedge(&self) -> bool {
+        self.budget.try_withdraw()
+    }
+
+    /// Returns the budget of a hedge that found no backend to run on
+    pub fn refund(&self) {
+        self.budget.refund();
+    }
+}
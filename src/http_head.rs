@@ -0,0 +1,406 @@
+use std::borrow::Cow;
+
+use anyhow::{Result, bail};
+
+/// Most header lines accepted in a request head
+///
+/// Response heads are not capped: upstreams may send many `Set-Cookie` fields, and
+/// their size is already bounded when read.
+const MAX_HEADERS: usize = 128;
+
+/// Header fields of a message head, in their original order, case and bytes
+///
+/// Values are kept as bytes: cookies and other fields may carry non-UTF-8 octets,
+/// which are forwarded untouched.
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Vec<(String, Vec<u8>)>);
+
+impl Headers {
+    fn from_parsed(parsed: &[httparse::Header<'_>]) -> Self {
+        Self(
+            parsed
+                .iter()
+                .map(|header| (header.name.to_string(), header.value.to_vec()))
+                .collect(),
+        )
+    }
+
+    /// Returns the first value of a header, ignoring name case
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns the first value of a header as text, with invalid UTF-8 replaced
+    pub fn get_str(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.get(name).map(String::from_utf8_lossy)
+    }
+
+    /// Returns every value of a header, ignoring name case
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn append(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        self.0.push((name.to_string(), value.into()));
+    }
+
+    /// Removes every field with the given name
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// Renames every field called `from`, keeping its position and value
+    pub fn rename(&mut self, from: &str, to: &str) {
+        for (name, _) in &mut self.0 {
+            if name.eq_ignore_ascii_case(from) {
+                *name = to.to_string();
+            }
+        }
+    }
+
+    /// Keeps only the fields for which `keep` returns true
+    pub fn retain(&mut self, mut keep: impl FnMut(&str, &[u8]) -> bool) {
+        self.0.retain(|(name, value)| keep(name, value));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_slice()))
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.0 {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Request line and headers of an HTTP/1.x request
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: String,
+    /// Request target as sent: authority form for CONNECT, usually absolute form otherwise
+    pub target: String,
+    /// Minor version of HTTP/1.x
+    pub version: u8,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    /// Parses a complete request head (ending with the blank line)
+    ///
+    /// Folded header lines are unfolded. Requests with ambiguous framing that could
+    /// smuggle a second request past the upstream are rejected.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let raw = unfold(raw);
+        let mut parsed = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut parsed);
+        match request.parse(&raw) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => bail!("incomplete request header"),
+            Err(err) => bail!("invalid request header: {err}"),
+        }
+
+        let head = Self {
+            method: request.method.unwrap_or_default().to_string(),
+            target: request.path.unwrap_or_default().to_string(),
+            version: request.version.unwrap_or(1),
+            headers: Headers::from_parsed(request.headers),
+        };
+        head.check_framing()?;
+        Ok(head)
+    }
+
+    pub fn is_connect(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+    }
+
+    /// Returns the lowercased target host without port: from the target of CONNECT
+    /// requests, from `Host` otherwise
+    pub fn target_host(&self) -> String {
+        let authority = if self.is_connect() {
+            Cow::Borrowed(self.target.as_str())
+        } else {
+            self.headers.get_str("host").unwrap_or_default()
+        };
+        let authority = authority.trim();
+        let host = match authority.strip_prefix('[') {
+            Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+            None => authority.split(':').next().unwrap_or_default(),
+        };
+        host.to_ascii_lowercase()
+    }
+
+    /// Rejects request-smuggling patterns: conflicting or repeated Content-Length,
+    /// Content-Length together with Transfer-Encoding, and a Transfer-Encoding that
+    /// does not end in `chunked`
+    fn check_framing(&self) -> Result<()> {
+        let lengths: Vec<&[u8]> = self.headers.get_all("content-length").collect();
+        let encodings: Vec<&[u8]> = self.headers.get_all("transfer-encoding").collect();
+
+        if lengths.len() > 1 {
+            bail!("request has {} Content-Length headers", lengths.len());
+        }
+        if let Some(length) = lengths.first()
+            && (length.is_empty() || !length.iter().all(u8::is_ascii_digit))
+        {
+            bail!(
+                "invalid Content-Length {:?}",
+                String::from_utf8_lossy(length)
+            );
+        }
+        if !lengths.is_empty() && !encodings.is_empty() {
+            bail!("request has both Content-Length and Transfer-Encoding");
+        }
+
+        if !encodings.is_empty() {
+            let joined = encodings
+                .iter()
+                .map(|value| String::from_utf8_lossy(value))
+                .collect::<Vec<_>>()
+                .join(",");
+            let last = joined.rsplit(',').next().unwrap_or_default().trim();
+            if !last.eq_ignore_ascii_case("chunked") {
+                bail!("unsupported Transfer-Encoding {joined:?}");
+            }
+        }
+
+        if self.headers.get_all("host").count() > 1 {
+            bail!("request has more than one Host header");
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(256);
+        out.extend_from_slice(
+            format!(
+                "{} {} HTTP/1.{}\r\n",
+                self.method, self.target, self.version
+            )
+            .as_bytes(),
+        );
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+/// Status line and headers of an HTTP/1.x response
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    /// Minor version of HTTP/1.x
+    pub version: u8,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    /// Creates an HTTP/1.1 response head without headers
+    pub fn new(status: u16, reason: &str) -> Self {
+        Self {
+            version: 1,
+            status,
+            reason: reason.to_string(),
+            headers: Headers::default(),
+        }
+    }
+
+    /// Parses a complete response head (ending with the blank line), unfolding folded lines
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let raw = unfold(raw);
+        // One slot per line fits every header the upstream sent
+        let lines = raw.iter().filter(|&&byte| byte == b'\n').count();
+        let mut parsed = vec![httparse::EMPTY_HEADER; lines];
+        let mut response = httparse::Response::new(&mut parsed);
+        match response.parse(&raw) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => bail!("incomplete response header"),
+            Err(err) => bail!("invalid response header: {err}"),
+        }
+
+        Ok(Self {
+            version: response.version.unwrap_or(1),
+            status: response.code.unwrap_or_default(),
+            reason: response.reason.unwrap_or_default().to_string(),
+            headers: Headers::from_parsed(response.headers),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(256);
+        out.extend_from_slice(
+            format!(
+                "HTTP/1.{} {} {}\r\n",
+                self.version, self.status, self.reason
+            )
+            .as_bytes(),
+        );
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+/// Returns true if `name` is a valid header field name (an RFC 9110 token)
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Replaces obs-fold (a line break followed by spaces or tabs) with spaces
+fn unfold(raw: &[u8]) -> Cow<'_, [u8]> {
+    let folded = |i: usize| {
+        raw[i] == b'\r'
+            && raw.get(i + 1) == Some(&b'\n')
+            && matches!(raw.get(i + 2), Some(b' ' | b'\t'))
+    };
+    if !(0..raw.len()).any(folded) {
+        return Cow::Borrowed(raw);
+    }
+
+    let mut out = raw.to_vec();
+    for i in 0..raw.len() {
+        if folded(i) {
+            out[i] = b' ';
+            out[i + 1] = b' ';
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> Result<RequestHead> {
+        RequestHead::parse(format!("POST /upload HTTP/1.1\r\n{headers}\r\n").as_bytes())
+    }
+
+    #[test]
+    fn rejects_repeated_content_length() {
+        let err = request("Host: a\r\nContent-Length: 5\r\nContent-Length: 5\r\n").unwrap_err();
+        assert!(err.to_string().contains("2 Content-Length"), "{err}");
+        assert!(request("Host: a\r\nContent-Length: 5\r\nContent-Length: 6\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_content_length() {
+        for length in ["", "+5", "5, 5", "0x10", "-1"] {
+            let headers = format!("Host: a\r\nContent-Length: {length}\r\n");
+            assert!(request(&headers).is_err(), "accepted {length:?}");
+        }
+        assert!(request("Host: a\r\nContent-Length: 42\r\n").is_ok());
+    }
+
+    #[test]
+    fn rejects_content_length_with_transfer_encoding() {
+        let err =
+            request("Host: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n").unwrap_err();
+        assert!(err.to_string().contains("both"), "{err}");
+    }
+
+    #[test]
+    fn transfer_encoding_must_end_in_chunked() {
+        assert!(request("Host: a\r\nTransfer-Encoding: gzip\r\n").is_err());
+        assert!(request("Host: a\r\nTransfer-Encoding: chunked, gzip\r\n").is_err());
+        assert!(
+            request("Host: a\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n")
+                .is_err()
+        );
+
+        assert!(request("Host: a\r\nTransfer-Encoding: gzip, Chunked\r\n").is_ok());
+        assert!(
+            request("Host: a\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n").is_ok()
+        );
+    }
+
+    #[test]
+    fn rejects_repeated_host() {
+        assert!(request("Host: a\r\nHost: b\r\n").is_err());
+    }
+
+    #[test]
+    fn unfolds_obs_fold() {
+        let head =
+            request("Host: a\r\nX-Long: first\r\n  second\r\n\tthird\r\nX-Next: 1\r\n").unwrap();
+        let value = head.headers.get_str("x-long").unwrap();
+        let words: Vec<&str> = value.split_whitespace().collect();
+        assert_eq!(words, ["first", "second", "third"]);
+        assert_eq!(head.headers.get_str("x-next").as_deref(), Some("1"));
+
+        let response = ResponseHead::parse(b"HTTP/1.1 200 OK\r\nX-Long: a\r\n b\r\n\r\n").unwrap();
+        let words: Vec<String> = response
+            .headers
+            .get_str("x-long")
+            .unwrap()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(words, ["a", "b"]);
+    }
+
+    #[test]
+    fn keeps_non_utf8_values_byte_for_byte() {
+        let raw = b"GET / HTTP/1.1\r\nHost: a\r\nCookie: id=\xff\xfe\x80\r\n\r\n";
+        let head = RequestHead::parse(raw).unwrap();
+        assert_eq!(head.headers.get("cookie"), Some(&b"id=\xff\xfe\x80"[..]));
+        assert_eq!(
+            head.headers.get_str("cookie").as_deref(),
+            Some("id=\u{fffd}\u{fffd}\u{fffd}")
+        );
+        assert_eq!(head.to_bytes(), raw);
+    }
+
+    #[test]
+    fn response_may_exceed_request_header_limit() {
+        let cookies = (0..MAX_HEADERS * 2)
+            .map(|i| format!("Set-Cookie: c{i}=v\r\n"))
+            .collect::<String>();
+        let raw = format!("HTTP/1.1 200 OK\r\n{cookies}\r\n");
+        let response = ResponseHead::parse(raw.as_bytes()).unwrap();
+        assert_eq!(
+            response.headers.get_all("set-cookie").count(),
+            MAX_HEADERS * 2
+        );
+        assert_eq!(response.to_bytes(), raw.as_bytes());
+
+        let headers = (0..MAX_HEADERS * 2)
+            .map(|i| format!("X-H{i}: v\r\n"))
+            .collect::<String>();
+        assert!(request(&headers).is_err());
+    }
+
+    #[test]
+    fn round_trips_header_order_and_case() {
+        let raw = b"HTTP/1.1 200 OK\r\nX-B: 1\r\nx-a: 2\r\nX-B: 3\r\n\r\n";
+        let response = ResponseHead::parse(raw).unwrap();
+        let values: Vec<&[u8]> = response.headers.get_all("x-b").collect();
+        assert_eq!(values, [b"1", b"3"]);
+        assert_eq!(response.to_bytes(), raw);
+    }
+
+    #[test]
+    fn finds_target_host() {
+        let connect = RequestHead::parse(b"CONNECT [::1]:443 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(connect.target_host(), "::1");
+        let get = request("Host: Example.COM:8080\r\n").unwrap();
+        assert_eq!(get.target_host(), "example.com");
+    }
+}
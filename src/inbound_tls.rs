@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use pingora_core::listeners::TlsAccept;
+use pingora_core::listeners::tls::TlsSettings;
+use pingora_core::tls::ext;
+use pingora_core::tls::nid::Nid;
+use pingora_core::tls::pkey::{PKey, Private};
+use pingora_core::tls::ssl::{SslRef, SslVerifyMode};
+use pingora_core::tls::x509::X509;
+use tracing::{info, warn};
+
+use crate::config::InboundTlsConfig;
+
+/// Builds listener TLS settings: certificate served via reloading callback, optional mTLS
+pub fn tls_settings(config: &InboundTlsConfig) -> Result<TlsSettings> {
+    let reloader = CertReloader::new(&config.cert, &config.key, config.reload_interval())?;
+    let mut settings = TlsSettings::with_callbacks(Box::new(reloader))
+        .context("failed to create TLS listener settings")?;
+
+    if let Some(client_ca) = &config.client_ca {
+        settings
+            .set_ca_file(client_ca)
+            .with_context(|| format!("failed to load client CA {}", client_ca.display()))?;
+        settings.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+
+    Ok(settings)
+}
+
+/// Serves the listener certificate, reloading it from disk when the files change
+struct CertReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    interval: Duration,
+    current: RwLock<Arc<LoadedCert>>,
+    last_check: Mutex<Instant>,
+}
+
+struct LoadedCert {
+    cert: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl CertReloader {
+    fn new(cert_path: &Path, key_path: &Path, interval: Duration) -> Result<Self> {
+        let loaded = Self::load(cert_path, key_path)?;
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            interval,
+            current: RwLock::new(Arc::new(loaded)),
+            last_check: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn load(cert_path: &Path, key_path: &Path) -> Result<LoadedCert> {
+        let cert_pem = fs::read(cert_path)
+            .with_context(|| format!("failed to read TLS certificate {}", cert_path.display()))?;
+        let key_pem = fs::read(key_path)
+            .with_context(|| format!("failed to read TLS key {}", key_path.display()))?;
+
+        let mut certs = X509::stack_from_pem(&cert_pem)
+            .with_context(|| format!("invalid TLS certificate {}", cert_path.display()))?;
+        if certs.is_empty() {
+            anyhow::bail!("no certificate found in {}", cert_path.display());
+        }
+        let cert = certs.remove(0);
+        let key = PKey::private_key_from_pem(&key_pem)
+            .with_context(|| format!("invalid TLS key {}", key_path.display()))?;
+
+        Ok(LoadedCert {
+            cert,
+            chain: certs,
+            key,
+            modified: (modified_time(cert_path), modified_time(key_path)),
+        })
+    }
+
+    /// Returns the current certificate, reloading it first if the files changed
+    fn current(&self) -> Arc<LoadedCert> {
+        let due = {
+            let mut last_check = self.last_check.lock().unwrap_or_else(|e| e.into_inner());
+            if last_check.elapsed() >= self.interval {
+                *last_check = Instant::now();
+                true
+            } else {
+                false
+            }
+        };
+
+        let current = Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()));
+        if !due {
+            return current;
+        }
+
+        let modified = (modified_time(&self.cert_path), modified_time(&self.key_path));
+        if modified == current.modified {
+            return current;
+        }
+
+        match Self::load(&self.cert_path, &self.key_path) {
+            Ok(loaded) => {
+                info!("Reloaded TLS certificate from {}", self.cert_path.display());
+                let loaded = Arc::new(loaded);
+                *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::clone(&loaded);
+                loaded
+            }
+            Err(err) => {
+                // Keep serving the previous certificate, e.g. while files are half-written
+                warn!("Failed to reload TLS certificate: {err:#}");
+                current
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TlsAccept for CertReloader {
+    async fn certificate_callback(&self, ssl: &mut SslRef) {
+        let loaded = self.current();
+        if let Err(err) = ext::ssl_use_certificate(ssl, &loaded.cert) {
+            warn!("Failed to set TLS certificate: {err}");
+            return;
+        }
+        for cert in &loaded.chain {
+            if let Err(err) = ext::ssl_add_chain_cert(ssl, cert) {
+                warn!("Failed to add TLS chain certificate: {err}");
+            }
+        }
+        if let Err(err) = ext::ssl_use_private_key(ssl, &loaded.key) {
+            warn!("Failed to set TLS private key: {err}");
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Maps verified client certificates to user identities
+pub struct ClientIdentities {
+    identities: HashMap<String, String>,
+}
+
+impl ClientIdentities {
+    pub fn new(identities: HashMap<String, String>) -> Self {
+        Self { identities }
+    }
+
+    /// Resolves the user for a TLS session
+    ///
+    /// Returns `Ok(None)` when no client certificate was presented, and an error when the
+    /// certificate subject is not in the configured identity map.
+    pub fn resolve(&self, ssl: &SslRef) -> Result<Option<String>> {
+        let Some(cert) = ssl.peer_certificate() else {
+            return Ok(None);
+        };
+
+        let common_name = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| String::from_utf8(entry.data().as_slice().to_vec()).ok())
+            .context("client certificate has no common name")?;
+
+        if self.identities.is_empty() {
+            return Ok(Some(common_name));
+        }
+
+        self.identities
+            .get(&common_name)
+            .cloned()
+            .map(Some)
+            .with_context(|| format!("client certificate {common_name} is not mapped to a user"))
+    }
+}
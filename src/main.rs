@@ -1,17 +1,47 @@
 mod proxy_handler;
 mod backend_pool;
+mod budget;
+mod circuit_breaker;
+mod cli;
+mod config;
+mod dns;
+mod error_response;
+mod exit_ip;
+mod geo;
+mod header_rules;
+mod hedge;
+mod http_head;
+mod inbound_tls;
+mod proxy_list;
+mod rate_limit;
+mod rewrite;
+mod sources;
+mod state;
+mod tunnel;
 mod upstream;
+mod usage;
+mod username_template;
 
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use clap::Parser;
 use pingora_core::server::Server;
-use pingora_core::server::configuration::Opt;
+use pingora_core::services::background::background_service;
 use pingora_core::services::listening::Service;
-use tracing::info;
+use tracing::{error, info, warn};
 
 use proxy_handler::ForwardProxy;
 use backend_pool::SimpleBackendPool;
-use upstream::load_backends_from_file;
+use cli::{Cli, Command, ServeArgs};
+use config::SelectionMode;
+use exit_ip::ExitIpProber;
+use geo::GeoDb;
+use state::{ProxyState, StateSnapshotter};
+use usage::UsageStore;
+use sources::ProxySources;
 
-fn main() {
+fn main() -> ExitCode {
     // Initialize logging
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -20,29 +50,81 @@ fn main() {
         )
         .init();
 
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Serve(args) => serve(args).map(|_| true),
+        Command::Validate(args) => cli::validate(&args),
+        Command::Check(args) => cli::check(&args),
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            error!("{err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the proxy server until shutdown
+fn serve(args: ServeArgs) -> anyhow::Result<()> {
     info!("Starting Pingora forward proxy server");
 
+    // Load service config, falling back to defaults when absent
+    let mut config = args.files.load_config()?;
+    if let Some(listen) = args.listen {
+        config.listen = listen;
+    }
+
     // Setup server
-    let opt = Opt::parse_args();
-    let mut server = Server::new(Some(opt)).unwrap();
+    let mut server = Server::new(Some(args.server))?;
     server.bootstrap();
 
-    // Load proxies from config file
-    let backends = load_backends_from_file("config/proxies.txt")
-        .expect("Failed to load proxy backends from config/proxies.txt");
+    // Proxies are loaded by the sources service once the server runs
+    let pool = SimpleBackendPool::new(Vec::new(), config.selection);
+    let geo = GeoDb::from_config(&config.geo)?;
+    let sources = ProxySources::new(
+        config.proxy_sources(),
+        &config.dns,
+        geo.clone(),
+        pool.clone(),
+    )?;
+
+    let state = Arc::new(ProxyState::default());
+    let prober = ExitIpProber::new(pool.clone(), state.clone(), &config, geo);
+    if prober.is_none() && config.selection == SelectionMode::DistinctExitIp {
+        warn!("selection distinct_exit_ip has no effect without exit_ip.echo_url");
+    }
 
-    info!("Loaded {} proxy backends", backends.len());
+    let usage = background_service("usage accounting", UsageStore::new(&config));
+    let snapshotter = StateSnapshotter::restore(state.clone(), usage.task(), &config.state);
 
-    // Create pool and proxy service
-    let pool = SimpleBackendPool::new(backends);
-    let proxy = ForwardProxy::new(pool);
+    // Create proxy service
+    let proxy = ForwardProxy::new(pool, &config)
+        .with_usage(usage.task())
+        .with_state(state);
     let mut proxy_service = Service::new("Forward TCP proxy".to_string(), proxy);
-    proxy_service.add_tcp("0.0.0.0:8890");
+    proxy_service.add_tcp(&config.listen);
+
+    info!("Forward proxy listening on {}", config.listen);
 
-    info!("Forward proxy listening on 0.0.0.0:8890");
+    if let Some(tls) = &config.tls {
+        let settings = inbound_tls::tls_settings(tls)?;
+        proxy_service.add_tls_with_settings(&tls.listen, None, settings);
+        info!("Forward proxy listening for TLS clients on {}", tls.listen);
+    }
 
     // Start server
     server.add_service(proxy_service);
+    server.add_service(background_service("proxy sources", sources));
+    server.add_service(usage);
+    if let Some(snapshotter) = snapshotter {
+        server.add_service(background_service("state snapshots", snapshotter));
+    }
+    if let Some(prober) = prober {
+        server.add_service(background_service("exit IP probes", prober));
+    }
     info!("Server configured, starting main loop");
     server.run_forever();
 }
@@ -1,121 +1,482 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result, anyhow, bail};
 use async_trait::async_trait;
-use dashmap::DashSet;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+use pingora_core::ErrorType;
 use pingora_core::apps::ServerApp;
 use pingora_core::connectors::TransportConnector;
 use pingora_core::protocols::Stream;
 use pingora_core::server::ShutdownWatch;
 use pingora_core::upstreams::peer::{BasicPeer, Peer};
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::time::timeout;
+use pingora_load_balancing::Backend;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::error::Elapsed;
+use tokio::time::{Instant, sleep, sleep_until, timeout};
 use tracing::debug;
+use url::Url;
 
-use crate::backend_pool::SimpleBackendPool;
+use crate::backend_pool::{Selected, SimpleBackendPool};
+use crate::budget::TokenBudget;
+use crate::circuit_breaker::CircuitBreakers;
+use crate::config::{
+    Config, NoMatchPolicy, PoolConfig, PoolMode, RetryConfig, TimeoutConfig, UpstreamTimeouts,
+};
+use crate::error_response::{AttemptRecord, ErrorReason, ErrorResponse};
+use crate::exit_ip::parse_exit_ip;
+use crate::header_rules::{self, HeaderRule, RuleContext, RuleTarget};
+use crate::hedge::Hedging;
+use crate::http_head::{Headers, RequestHead, ResponseHead};
+use crate::inbound_tls::ClientIdentities;
+use crate::rewrite;
+use crate::tunnel::{self, TunnelPolicy, TunnelTracker};
+use crate::state::ProxyState;
 use crate::upstream::ProxyMetadata;
+use crate::usage::UsageStore;
+use crate::username_template::SessionHints;
 
 /// Forward proxy that distributes requests across upstream proxies
 pub struct ForwardProxy {
     pool: Arc<SimpleBackendPool>,
-    banned: Arc<DashSet<String>>,
+    state: Arc<ProxyState>,
+    circuits: CircuitBreakers,
+    hedging: Option<Hedging>,
+    retries: RetryConfig,
+    /// Shared by all requests so retries stay a fraction of first attempts
+    retry_budget: TokenBudget,
     connector: TransportConnector,
+    tunnels: Arc<TunnelTracker>,
+    tunnel_policy: TunnelPolicy,
+    client_identities: Option<ClientIdentities>,
+    on_geo_mismatch: NoMatchPolicy,
+    /// Longest a request queues when every proxy is rate limited
+    limit_wait: Duration,
+    pools: HashMap<String, PoolConfig>,
+    usage: Option<Arc<UsageStore>>,
+    session_ttl: Duration,
+    timeouts: TimeoutConfig,
+    /// Rules for every pool; each pool's own rules run after these
+    header_rules: Vec<HeaderRule>,
 }
 
 impl ForwardProxy {
     const LB_MAX_ITERATIONS: usize = 256;
     const HEADER_LIMIT: usize = 64 * 1024;
-    const REQUEST_TIMEOUT_SECS: u64 = 30;
-    const RESPONSE_TIMEOUT_SECS: u64 = 30;
 
     /// Creates a new ForwardProxy
-    pub fn new(pool: Arc<SimpleBackendPool>) -> Self {
+    pub fn new(pool: Arc<SimpleBackendPool>, config: &Config) -> Self {
         Self {
             pool,
-            banned: Arc::new(DashSet::new()),
+            state: Arc::new(ProxyState::default()),
+            circuits: CircuitBreakers::new(config.circuit_breaker.clone()),
+            hedging: Hedging::new(&config.hedging),
+            retries: config.retries.clone(),
+            // Starts full so the first failures after a start are not left without a retry
+            retry_budget: TokenBudget::new(config.retries.budget_percent, TokenBudget::MAX_TOKENS),
             connector: TransportConnector::new(None),
+            tunnels: TunnelTracker::new(config.shutdown.grace_period()),
+            tunnel_policy: config.tunnel.policy(),
+            client_identities: config
+                .tls
+                .as_ref()
+                .filter(|tls| tls.client_ca.is_some())
+                .map(|tls| ClientIdentities::new(tls.identities.clone())),
+            on_geo_mismatch: config.geo.on_no_match,
+            limit_wait: config.rate_limit.max_wait(),
+            pools: config.pools.clone(),
+            usage: None,
+            session_ttl: config.sessions.ttl(),
+            timeouts: config.timeouts.clone(),
+            header_rules: config.header_rules.clone(),
         }
     }
 
-    fn request_timeout() -> Duration {
-        Duration::from_secs(Self::REQUEST_TIMEOUT_SECS)
+    /// Shares bans, backend stats and sticky sessions with a state snapshotter
+    pub fn with_state(mut self, state: Arc<ProxyState>) -> Self {
+        self.state = state;
+        self
     }
 
-    fn response_timeout() -> Duration {
-        Duration::from_secs(Self::RESPONSE_TIMEOUT_SECS)
+    /// Records tunnel bandwidth and enforces budgets using `usage`
+    pub fn with_usage(mut self, usage: Arc<UsageStore>) -> Self {
+        self.usage = Some(usage);
+        self
+    }
+
+    /// Returns the upstream timeouts for a request's pool and requested overrides
+    fn upstream_timeouts(&self, hints: &SessionHints) -> UpstreamTimeouts {
+        let pool = self.pools.get(hints.pool()).map(|pool| &pool.timeouts);
+        self.timeouts.upstream(pool, &hints.timeouts)
+    }
+
+    /// Opens a CONNECT tunnel to `target` through one backend and returns the proxy's status code
+    pub async fn probe_backend(&self, backend: &Backend, target: &str) -> Result<u16> {
+        let metadata = backend
+            .ext
+            .get::<ProxyMetadata>()
+            .context("backend missing metadata")?;
+        let initial = InitialRequest::probe(RequestHead::parse(
+            format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").as_bytes(),
+        )?);
+
+        match self
+            .try_proxy_once(&backend.addr.to_string(), metadata, &initial)
+            .await?
+        {
+            AttemptOutcome::Success { status_code, .. } | AttemptOutcome::Retry { status_code, .. } => {
+                Ok(status_code)
+            }
+        }
+    }
+
+    /// Fetches a plain `http://` IP-echo URL through one backend and returns the reported address
+    pub async fn probe_exit_ip(&self, backend: &Backend, echo_url: &str) -> Result<IpAddr> {
+        const BODY_LIMIT: usize = 4096;
+
+        let metadata = backend
+            .ext
+            .get::<ProxyMetadata>()
+            .context("backend missing metadata")?;
+        let url = Url::parse(echo_url).with_context(|| format!("invalid echo URL {echo_url}"))?;
+        if url.scheme() != "http" {
+            bail!("echo URL must use http://, got {echo_url}");
+        }
+        let host = url.host_str().context("echo URL has no host")?;
+
+        let initial = InitialRequest::probe(RequestHead::parse(
+            format!(
+                "GET {echo_url} HTTP/1.1\r\nHost: {host}\r\nAccept: text/plain\r\nConnection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )?);
+
+        let (mut upstream, response, mut body) = match self
+            .try_proxy_once(&backend.addr.to_string(), metadata, &initial)
+            .await?
+        {
+            AttemptOutcome::Success {
+                upstream,
+                response,
+                response_body_prefix,
+                status_code: 200..=299,
+                ..
+            } => (upstream, response, response_body_prefix),
+            AttemptOutcome::Success { status_code, .. } | AttemptOutcome::Retry { status_code, .. } => {
+                bail!("echo request returned {status_code}")
+            }
+        };
+
+        // Proxies may keep the connection open despite `Connection: close`
+        let content_length = response
+            .headers
+            .get_str("content-length")
+            .and_then(|value| value.trim().parse::<usize>().ok());
+        let limit = content_length.unwrap_or(BODY_LIMIT).min(BODY_LIMIT);
+
+        let first_byte_timeout = self.upstream_timeouts(&initial.hints).first_byte;
+        let mut chunk = [0u8; 1024];
+        while body.len() < limit {
+            let n = timeout(first_byte_timeout, upstream.read(&mut chunk))
+                .await
+                .context("timeout while reading echo response")??;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..n]);
+        }
+
+        parse_exit_ip(&body)
+    }
+
+    /// Identifies the client from its TLS client certificate, if mTLS is enabled
+    fn client_context(&self, downstream: &Stream) -> Result<ClientContext> {
+        let user = match (downstream.get_ssl(), &self.client_identities) {
+            (Some(ssl), Some(identities)) => identities.resolve(ssl)?,
+            _ => None,
+        };
+        let ip = downstream.get_socket_digest().and_then(|digest| {
+            digest
+                .peer_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip())
+        });
+        Ok(ClientContext { user, ip })
+    }
+
+    /// Applies the global and then the pool's header rules for `target`
+    fn apply_header_rules(
+        &self,
+        target: RuleTarget,
+        headers: &mut Headers,
+        context: &RuleContext<'_>,
+    ) {
+        let pool_rules = self
+            .pools
+            .get(context.hints.pool())
+            .map(|pool| pool.header_rules.as_slice())
+            .unwrap_or_default();
+        header_rules::apply(
+            self.header_rules.iter().chain(pool_rules),
+            target,
+            headers,
+            context,
+        );
     }
 
     /// Handles a complete proxy connection: reads request, selects backend, proxies with retries
     async fn handle_connection(
         &self,
         mut downstream: Stream,
+        client: &ClientContext,
         shutdown: &ShutdownWatch,
     ) -> Result<()> {
-        if *shutdown.borrow() {
+        if *shutdown.borrow() || self.tunnels.is_draining() {
             debug!("Shutdown in progress; rejecting new downstream session");
             return Ok(());
         }
 
-        let initial = match Self::read_initial_request(&mut downstream).await {
-            Ok(req) => req,
+        // Stop waiting for a request on idle connections once shutdown starts
+        let mut stopping = shutdown.clone();
+        let (header, body_prefix) = tokio::select! {
+            read = Self::read_http_message(&mut downstream, Vec::new(), Self::HEADER_LIMIT, self.timeouts.client_header()) => match read {
+                Ok(read) => read,
+                Err(err) => {
+                    debug!("Failed to read initial downstream request: {err:#}");
+                    return Err(err.context("failed to read downstream request header"));
+                }
+            },
+            _ = stopping.wait_for(|stopping| *stopping) => {
+                debug!("Shutdown started before a request arrived; closing downstream session");
+                return Ok(());
+            }
+        };
+        let mut initial = match Self::parse_initial_request(header, body_prefix) {
+            Ok(initial) => initial,
             Err(err) => {
-                debug!("Failed to read initial downstream request: {err:#}");
+                debug!("Malformed downstream request: {err:#}");
+                let response = ErrorResponse::new(ErrorReason::BadRequest, format!("{err:#}"));
+                Self::respond_error(&mut downstream, &response).await?;
                 return Err(err);
             }
         };
 
-        let total_backends = self.pool.len();
+        self.resolve_pool(&mut initial.hints);
+        if let Err(err) = self.rewrite_for_pool(&mut initial) {
+            debug!(
+                "Cannot forward request to pool {}: {err:#}",
+                initial.hints.pool()
+            );
+            let response = ErrorResponse::new(ErrorReason::BadRequest, format!("{err:#}"))
+                .with_json(initial.wants_json);
+            Self::respond_error(&mut downstream, &response).await?;
+            return Err(err);
+        }
+
+        let mut total_backends = self.available_backends(&initial.hints);
+        if total_backends == 0 && initial.hints.has_location() {
+            let anywhere = initial.hints.without_location();
+            let location = initial.hints.location();
+            if self.available_backends(&anywhere) > 0 {
+                match self.on_geo_mismatch {
+                    NoMatchPolicy::Reject => {
+                        debug!("No proxy matches {location}; returning 503");
+                        let response = ErrorResponse::new(
+                            ErrorReason::NoLocationMatch,
+                            format!("No proxy available for {location}"),
+                        )
+                        .with_json(initial.wants_json);
+                        Self::respond_error(&mut downstream, &response).await?;
+                        bail!("no proxy matches {location}");
+                    }
+                    NoMatchPolicy::Any => {
+                        debug!("No proxy matches {location}; falling back to any location");
+                        initial.hints = anywhere;
+                        total_backends = self.available_backends(&initial.hints);
+                    }
+                }
+            }
+        }
+
         if total_backends == 0 {
             debug!("No upstream proxies configured; returning 503");
-            Self::respond_service_unavailable(&mut downstream).await?;
+            let response = ErrorResponse::new(
+                ErrorReason::NoProxies,
+                format!("No proxies configured for pool {}", initial.hints.pool()),
+            )
+            .with_json(initial.wants_json);
+            Self::respond_error(&mut downstream, &response).await?;
             bail!("no upstream proxies configured");
         }
 
-        let mut attempted = HashSet::new();
+        self.buffer_expected_body(&mut downstream, &mut initial)
+            .await
+            .context("failed to read downstream request body")?;
+
+        let context = RuleContext {
+            client_ip: client.ip,
+            user: client.user.as_deref(),
+            hints: &initial.hints,
+            host: &initial.host,
+        };
+        self.apply_header_rules(RuleTarget::Request, &mut initial.head.headers, &context);
+
+        if initial.is_connect
+            && let Some(hedging) = &self.hedging
+        {
+            hedging.deposit();
+        }
+
+        self.retry_budget.deposit();
+        let deadline = Instant::now() + self.retries.deadline();
+        let mut attempts = Attempts::default();
         let mut last_error: Option<anyhow::Error> = None;
+        let mut stop = StopReason::Exhausted;
+        let mut body_committed = false;
 
         // Try proxying through available backends
         for _ in 0..Self::LB_MAX_ITERATIONS {
-            if attempted.len() >= total_backends {
+            if attempts.tried.len() >= total_backends {
+                break;
+            }
+            if attempts.log.len() >= self.retries.max_attempts.max(1) as usize {
+                stop = StopReason::MaxAttempts;
+                break;
+            }
+            if Instant::now() >= deadline {
+                stop = StopReason::Deadline;
                 break;
             }
 
-            let backend = match self.pool.select() {
-                Some(b) => b,
-                None => break,
+            // Pay for a retry before selecting so a refused one claims no circuit trial
+            let is_retry = !attempts.log.is_empty();
+            if is_retry && !self.retry_budget.try_withdraw() {
+                debug!("Retry budget exhausted; not retrying");
+                stop = StopReason::RetryBudget;
+                break;
+            }
+            let refund_retry = || {
+                if is_retry {
+                    self.retry_budget.refund();
+                }
             };
 
-            let backend_addr = backend.addr.to_string();
-
-            if self.banned.contains(&backend_addr) {
-                attempted.insert(backend_addr);
-                continue;
-            }
+            let backend = match self
+                .select_backend(&initial.hints, &attempts.tried, deadline)
+                .await
+            {
+                Some(Selected::Backend(b)) => b,
+                Some(Selected::Limited(_)) => {
+                    refund_retry();
+                    debug!("All matching proxies are rate limited; returning 503");
+                    let response =
+                        ErrorResponse::new(ErrorReason::RateLimited, "All proxies are rate limited")
+                            .with_json(initial.wants_json)
+                            .with_attempts(attempts.log);
+                    Self::respond_error(&mut downstream, &response).await?;
+                    bail!("all proxies are rate limited");
+                }
+                None => {
+                    refund_retry();
+                    break;
+                }
+            };
 
-            if !attempted.insert(backend_addr.clone()) {
+            let Some(candidate) = self.claim(&backend, &mut attempts.tried) else {
+                refund_retry();
                 continue;
-            }
+            };
 
-            let metadata = match backend.ext.get::<ProxyMetadata>() {
-                Some(m) => m.clone(),
-                None => {
-                    debug!("Backend {backend_addr} missing metadata; skipping");
-                    continue;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // An attempt cut off by the deadline reports nothing; a circuit trial it held expires
+            let Ok((candidate, result)) = timeout(
+                remaining,
+                self.attempt_hedged(candidate, &initial, &mut attempts),
+            )
+            .await
+            else {
+                stop = StopReason::Deadline;
+                break;
+            };
+            // A 100 Continue commits the body to this upstream, so the final response
+            // decides the attempt and a failure ends the request
+            let result = match result {
+                Ok(AttemptOutcome::Success {
+                    status_code: 100,
+                    upstream,
+                    mut interim,
+                    response,
+                    response_body_prefix,
+                    ..
+                }) => {
+                    body_committed = true;
+                    interim.extend_from_slice(&response.to_bytes());
+                    match self
+                        .finish_continue(
+                            &mut downstream,
+                            &candidate,
+                            upstream,
+                            interim,
+                            response_body_prefix,
+                            &initial,
+                        )
+                        .await
+                    {
+                        Ok(result) => result,
+                        Err(err) => {
+                            self.circuits.release(&candidate.id);
+                            return Err(err);
+                        }
+                    }
                 }
+                result => result,
             };
+            self.record_attempt(&candidate, &result, &mut attempts);
+            let Candidate {
+                id: backend_id,
+                addr: backend_addr,
+                metadata,
+                ..
+            } = candidate;
 
-            match self
-                .try_proxy_once(&backend_addr, &metadata, &initial)
-                .await
-            {
+            match result {
                 Ok(AttemptOutcome::Success {
                     mut upstream,
-                    response_header,
+                    interim,
+                    mut response,
                     response_body_prefix,
                     status_code,
+                    body_sent,
                 }) => {
+                    if let Some(session) = &initial.hints.session {
+                        let ttl = initial
+                            .hints
+                            .lifetime
+                            .map(|minutes| Duration::from_secs(u64::from(minutes) * 60))
+                            .unwrap_or(self.session_ttl);
+                        self.state.bind_session(session, &backend_id, ttl);
+                    }
+
+                    // A refused CONNECT is relayed as is, marked so clients can tell it from a tunnel
+                    if initial.is_connect && status_code >= 400 {
+                        response
+                            .headers
+                            .append("X-Proxywar-Error", ErrorReason::TargetRejected.code());
+                    }
+                    // Only a tunnel outlives the exchange; anything else is closed after it
+                    let is_tunnel = status_code == 101
+                        || (initial.is_connect && (200..300).contains(&status_code));
+                    rewrite::for_client(&mut response, is_tunnel);
+                    self.apply_header_rules(RuleTarget::Response, &mut response.headers, &context);
+                    let response_header = response.to_bytes();
+                    downstream
+                        .write_all(&interim)
+                        .await
+                        .context("failed to forward interim responses")?;
                     downstream
                         .write_all(&response_header)
                         .await
@@ -132,22 +493,79 @@ impl ForwardProxy {
                         .context("failed to flush downstream response")?;
 
                     if initial.is_connect {
-                        debug!("CONNECT tunnel established via {backend_addr}");
+                        debug!(
+                            "CONNECT tunnel established via {backend_id} ({backend_addr}) for user {}",
+                            client.user.as_deref().unwrap_or("-")
+                        );
+                    } else if status_code == 101 {
+                        debug!(
+                            "Switched protocols to {} via {backend_id} ({backend_addr})",
+                            response.headers.get_str("upgrade").unwrap_or_default()
+                        );
+                    } else if let Some(protocol) = &initial.upgrade {
+                        debug!(
+                            "{backend_id} declined the upgrade to {protocol} with status {status_code}"
+                        );
                     } else {
-                        debug!("Forwarded response from {backend_addr} with status {status_code}");
+                        debug!("Forwarded response from {backend_id} with status {status_code}");
                     }
 
                     if matches!(status_code, 407 | 402 | 511) {
                         debug!(
-                            "Auth failure status surfaced in success branch; banning {backend_addr}"
+                            "Auth failure status surfaced in success branch; banning {backend_id}"
                         );
-                        self.banned.insert(backend_addr);
+                        self.state.ban(&backend_id);
                         return Ok(());
                     }
 
-                    if let Err(err) = io::copy_bidirectional(&mut downstream, &mut upstream).await {
-                        debug!("Bidirectional stream with {backend_addr} terminated: {err}");
-                    }
+                    // Headers count toward usage along with the first tunnel bytes
+                    let mut overhead = (initial.head.to_bytes().len()
+                        + initial.body_prefix.len()
+                        + interim.len()
+                        + response_header.len()
+                        + response_body_prefix.len()) as u64
+                        + body_sent;
+                    let record_usage = |bytes: u64| {
+                        let Some(usage) = &self.usage else {
+                            return true;
+                        };
+                        usage.record(
+                            &metadata,
+                            client.user.as_deref(),
+                            bytes + std::mem::take(&mut overhead),
+                        );
+                        !usage.is_exhausted(&metadata)
+                    };
+
+                    // The client's next request is left unread, so it never reaches upstream
+                    let sent = initial.body_prefix.len() as u64 + body_sent;
+                    let upload_limit = initial
+                        .body_length
+                        .filter(|_| !is_tunnel)
+                        .map(|length| length.saturating_sub(sent));
+
+                    let tunnel = self.tunnels.register();
+                    let report = tunnel::copy_bidirectional_with_policy(
+                        &mut downstream,
+                        &mut upstream,
+                        TunnelPolicy {
+                            // The proxy answered CONNECT itself; the target speaks in the tunnel
+                            first_byte_timeout: initial
+                                .is_connect
+                                .then(|| self.upstream_timeouts(&initial.hints).first_byte),
+                            upload_limit,
+                            ..self.tunnel_policy
+                        },
+                        tunnel.force_closed(shutdown),
+                        record_usage,
+                    )
+                    .await;
+                    drop(tunnel);
+
+                    debug!(
+                        "Tunnel via {backend_id} closed: reason={} up={}B down={}B duration={:?}",
+                        report.reason, report.bytes_up, report.bytes_down, report.duration
+                    );
 
                     let _ = downstream.shutdown().await;
                     let _ = upstream.shutdown().await;
@@ -159,19 +577,44 @@ impl ForwardProxy {
                     banned_count,
                 }) => {
                     debug!(
-                        "Proxy {backend_addr} returned {status_code}; banned (total banned: {banned_count}), retrying"
+                        "Proxy {backend_id} returned {status_code}; banned (total banned: {banned_count})"
                     );
+                    if body_committed {
+                        stop = StopReason::BodySent;
+                        break;
+                    }
                     continue;
                 }
                 Err(err) => {
-                    debug!("Attempt with {backend_addr} failed: {err:#}");
+                    debug!("Attempt with {backend_id} ({backend_addr}) failed: {err:#}");
                     last_error = Some(err);
+                    if body_committed {
+                        stop = StopReason::BodySent;
+                        break;
+                    }
                     continue;
                 }
             }
         }
 
-        Self::respond_service_unavailable(&mut downstream).await?;
+        let reason = Self::failure_reason(stop, &attempts.log);
+        debug!(
+            "Giving up after {} attempts ({}, {}): {}",
+            attempts.log.len(),
+            stop.as_str(),
+            reason.code(),
+            attempts
+                .log
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let response = ErrorResponse::new(reason, reason.summary())
+            .with_header("X-Proxywar-Stop-Reason", stop.as_str().to_string())
+            .with_attempts(attempts.log)
+            .with_json(initial.wants_json);
+        Self::respond_error(&mut downstream, &response).await?;
         if let Some(err) = last_error {
             Err(err)
         } else {
@@ -179,31 +622,461 @@ impl ForwardProxy {
         }
     }
 
-    /// Reads and parses the initial HTTP request from client
-    async fn read_initial_request(stream: &mut Stream) -> Result<InitialRequest> {
-        let (header, body_prefix) =
-            Self::read_http_message(stream, Self::HEADER_LIMIT, Self::request_timeout())
-                .await
-                .context("failed to read downstream request header")?;
+    /// Picks the error for a request that stopped without a successful attempt
+    fn failure_reason(stop: StopReason, log: &[AttemptRecord]) -> ErrorReason {
+        match stop {
+            StopReason::Deadline => ErrorReason::DeadlineExceeded,
+            StopReason::RetryBudget => ErrorReason::RetryBudgetExhausted,
+            StopReason::Exhausted | StopReason::MaxAttempts | StopReason::BodySent => {
+                match log.last() {
+                    None => ErrorReason::NoHealthyProxies,
+                    Some(_) if log.iter().all(AttemptRecord::is_auth_failure) => {
+                        ErrorReason::UpstreamAuthFailed
+                    }
+                    Some(last) if last.is_timeout() => ErrorReason::UpstreamTimeout,
+                    Some(_) => ErrorReason::UpstreamError,
+                }
+            }
+        }
+    }
 
-        let is_connect = Self::is_connect_request(&header)?;
+    /// Turns a selected backend into an attempt, marking it attempted
+    ///
+    /// Returns None when the backend must be skipped: it lacks metadata, was already
+    /// attempted, is banned, or another request took its half-open circuit's only trial.
+    /// A skipped backend gets back the rate limit slot its selection took.
+    fn claim(&self, backend: &Backend, attempted: &mut HashSet<String>) -> Option<Candidate> {
+        let addr = backend.addr.to_string();
+        let Some(metadata) = backend.ext.get::<ProxyMetadata>() else {
+            debug!("Backend {addr} missing metadata; skipping");
+            return None;
+        };
+        let id = metadata.id.clone();
+
+        if !attempted.insert(id.clone())
+            || self.state.is_banned(&id)
+            || !self.circuits.try_pass(&id)
+        {
+            // Selection took a rate limit slot for a request this backend will not send
+            if let Some(limiter) = &metadata.limiter {
+                limiter.refund();
+            }
+            return None;
+        }
+
+        Some(Candidate {
+            id,
+            addr,
+            metadata: metadata.clone(),
+            started: Instant::now(),
+        })
+    }
+
+    /// Runs the attempt on `primary`, hedging a CONNECT with a second backend when no
+    /// response header arrived within the hedge delay and the hedge budget allows it
+    ///
+    /// Returns the first success, or the last failure; a losing attempt is dropped,
+    /// closing its connection and giving back any circuit trial it held.
+    async fn attempt_hedged(
+        &self,
+        primary: Candidate,
+        initial: &InitialRequest,
+        attempts: &mut Attempts,
+    ) -> (Candidate, Result<AttemptOutcome>) {
+        let first = self.attempt(&primary, initial);
+        tokio::pin!(first);
+
+        let Some(hedging) = self.hedging.as_ref().filter(|_| initial.is_connect) else {
+            return (primary, first.await);
+        };
+        tokio::select! {
+            result = &mut first => return (primary, result),
+            _ = sleep(hedging.delay()) => {}
+        }
+
+        // Check the budget first so a hedge it cannot pay for never claims a circuit trial
+        if !hedging.try_hedge() {
+            return (primary, first.await);
+        }
+        let Some(hedge) = self.select_hedge(&initial.hints, &mut attempts.tried) else {
+            hedging.refund();
+            return (primary, first.await);
+        };
+        debug!(
+            "No response from {} after {:?}; hedging with {}",
+            primary.id,
+            hedging.delay(),
+            hedge.id
+        );
+
+        let second = self.attempt(&hedge, initial);
+        tokio::pin!(second);
+        tokio::select! {
+            result = &mut first => {
+                if matches!(result, Ok(AttemptOutcome::Success { .. })) {
+                    // The cancelled hedge reports nothing; free its circuit trial
+                    self.circuits.release(&hedge.id);
+                    return (primary, result);
+                }
+                self.record_attempt(&primary, &result, attempts);
+                (hedge, second.await)
+            }
+            result = &mut second => {
+                if matches!(result, Ok(AttemptOutcome::Success { .. })) {
+                    self.circuits.release(&primary.id);
+                    return (hedge, result);
+                }
+                self.record_attempt(&hedge, &result, attempts);
+                (primary, first.await)
+            }
+        }
+    }
+
+    /// Proxies the request once through a candidate, owning what the attempt needs
+    fn attempt<'a>(
+        &'a self,
+        candidate: &Candidate,
+        initial: &'a InitialRequest,
+    ) -> impl Future<Output = Result<AttemptOutcome>> + use<'a> {
+        let addr = candidate.addr.clone();
+        let metadata = candidate.metadata.clone();
+        async move { self.try_proxy_once(&addr, &metadata, initial).await }
+    }
+
+    /// Claims a backend for a hedge without waiting for rate limits
+    fn select_hedge(
+        &self,
+        hints: &SessionHints,
+        attempted: &mut HashSet<String>,
+    ) -> Option<Candidate> {
+        // Bounded like the main loop; claim only fails for backends that became unusable
+        for _ in 0..Self::LB_MAX_ITERATIONS {
+            let selected = self
+                .pool
+                .select(hints, |backend| self.is_unusable(backend, attempted));
+            let Some(Selected::Backend(backend)) = selected else {
+                return None;
+            };
+            if let Some(candidate) = self.claim(&backend, attempted) {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    /// Updates backend stats and circuits with the result of one attempt and logs it
+    fn record_attempt(
+        &self,
+        candidate: &Candidate,
+        result: &Result<AttemptOutcome>,
+        attempts: &mut Attempts,
+    ) {
+        let (status, error) = match result {
+            Ok(
+                AttemptOutcome::Success { status_code, .. }
+                | AttemptOutcome::Retry { status_code, .. },
+            ) => (Some(*status_code), None),
+            Err(err) if is_timeout(err) => (None, Some("timeout")),
+            Err(err) if is_protocol_violation(err) => (None, Some("protocol_error")),
+            Err(_) => (None, Some("error")),
+        };
+        attempts.log.push(AttemptRecord {
+            proxy: self.retries.expose_proxies.then(|| candidate.addr.clone()),
+            status,
+            error,
+            duration_ms: candidate.started.elapsed().as_millis() as u64,
+        });
+
+        match result {
+            Ok(AttemptOutcome::Success { .. }) => {
+                self.state
+                    .record_success(&candidate.id, candidate.started.elapsed());
+                self.circuits.record_success(&candidate.id);
+            }
+            Ok(AttemptOutcome::Retry { .. }) => {
+                // Auth failures ban the proxy; the circuit only tracks transport errors
+                self.circuits.record_success(&candidate.id);
+                self.state.record_failure(&candidate.id);
+            }
+            Err(err) if is_protocol_violation(err) => {
+                // Neither a success nor a transport failure; free a circuit trial it held
+                self.state.record_failure(&candidate.id);
+                self.circuits.release(&candidate.id);
+            }
+            Err(_) => {
+                self.state.record_failure(&candidate.id);
+                self.circuits.record_failure(&candidate.id);
+            }
+        }
+    }
+
+    /// Returns true if the backend was attempted, is banned, over budget or behind an open circuit
+    fn is_unusable(&self, backend: &Backend, attempted: &HashSet<String>) -> bool {
+        backend.ext.get::<ProxyMetadata>().is_some_and(|metadata| {
+            attempted.contains(&metadata.id)
+                || self.state.is_banned(&metadata.id)
+                || self.circuits.is_blocked(&metadata.id)
+                || self.over_budget(metadata)
+        })
+    }
+
+    /// Returns true if the backend's provider has exhausted its bandwidth budget
+    fn over_budget(&self, metadata: &ProxyMetadata) -> bool {
+        self.usage
+            .as_ref()
+            .is_some_and(|usage| usage.is_exhausted(metadata))
+    }
+
+    /// Counts the distinct backends that can serve a request, excluding those over budget
+    fn available_backends(&self, hints: &SessionHints) -> usize {
+        self.pool
+            .identities_matching(hints, |metadata| self.over_budget(metadata))
+    }
+
+    /// Follows pool fallbacks while the requested pool has no backend within budget
+    fn resolve_pool(&self, hints: &mut SessionHints) {
+        let mut anywhere = hints.without_location();
+        // Bounded by the number of pools so a fallback cycle cannot loop forever
+        for _ in 0..=self.pools.len() {
+            if self.available_backends(&anywhere) > 0 {
+                break;
+            }
+            let Some(fallback) = self
+                .pools
+                .get(anywhere.pool())
+                .and_then(|pool| pool.fallback.clone())
+            else {
+                break;
+            };
+            debug!(
+                "Pool {} has no proxies within budget; using {fallback}",
+                anywhere.pool()
+            );
+            anywhere.pool = Some(fallback);
+        }
+        hints.pool = anywhere.pool;
+    }
+
+    /// Rewrites the request for the kind of backends in its pool: absolute form for
+    /// upstream proxies, origin form for origin servers
+    fn rewrite_for_pool(&self, initial: &mut InitialRequest) -> Result<()> {
+        initial.mode = self
+            .pools
+            .get(initial.hints.pool())
+            .map_or_else(PoolMode::default, |pool| pool.mode);
+        match initial.mode {
+            PoolMode::Proxy => rewrite::for_upstream_proxy(&mut initial.head)?,
+            PoolMode::Origin => rewrite::for_origin(&mut initial.head)?,
+        }
+        initial.host = initial.head.target_host();
+        Ok(())
+    }
+
+    /// Selects a backend not yet attempted, banned, over budget or behind an open circuit
+    ///
+    /// A sticky session keeps its pinned backend while that one is usable. When every
+    /// candidate is rate limited, waits for one to free up for at most the configured
+    /// `rate_limit.max_wait_ms`, and never past the request's `deadline`.
+    async fn select_backend(
+        &self,
+        hints: &SessionHints,
+        attempted: &HashSet<String>,
+        deadline: Instant,
+    ) -> Option<Selected> {
+        if let Some(session) = &hints.session
+            && let Some(id) = self.state.session_backend(session)
+            && !attempted.contains(&id)
+            && !self.state.is_banned(&id)
+            && !self.circuits.is_blocked(&id)
+            && let Some(backend) = self.pool.select_id(&id, hints)
+        {
+            match backend.ext.get::<ProxyMetadata>() {
+                Some(metadata) if self.over_budget(metadata) => {
+                    if let Some(limiter) = &metadata.limiter {
+                        limiter.refund();
+                    }
+                }
+                _ => return Some(Selected::Backend(backend)),
+            }
+        }
+
+        let deadline = deadline.min(Instant::now() + self.limit_wait);
+        loop {
+            let selected = self
+                .pool
+                .select(hints, |backend| self.is_unusable(backend, attempted));
+
+            match selected {
+                Some(Selected::Limited(wait)) if Instant::now() + wait <= deadline => {
+                    sleep(wait).await;
+                }
+                other => return other,
+            }
+        }
+    }
+
+    /// Parses the initial HTTP request read from the client
+    fn parse_initial_request(header: Vec<u8>, mut body_prefix: Vec<u8>) -> Result<InitialRequest> {
+        let mut head = RequestHead::parse(&header)?;
+        let is_connect = head.is_connect();
+        let wants_json = Self::accepts_json(&head);
+        let hints = Self::take_session_hints(&mut head)?;
+        let upgrade = (!is_connect && rewrite::is_upgrade(&head.headers)).then(|| {
+            head.headers
+                .get_str("upgrade")
+                .unwrap_or_default()
+                .into_owned()
+        });
+        // HTTP/1.0 clients cannot take 1xx responses, so their Expect is ignored
+        let awaits_continue = !is_connect
+            && head.version >= 1
+            && head.headers.get_all("expect").any(|value| {
+                String::from_utf8_lossy(value)
+                    .trim()
+                    .eq_ignore_ascii_case("100-continue")
+            });
+        let body_length = (!is_connect && !head.headers.contains("transfer-encoding")).then(|| {
+            head.headers
+                .get_str("content-length")
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .unwrap_or(0)
+        });
+        // Anything after the body is a pipelined request, which is never forwarded
+        if let Some(length) = body_length {
+            body_prefix.truncate(usize::try_from(length).unwrap_or(usize::MAX));
+        }
         Ok(InitialRequest {
-            header,
+            // Set once the request is rewritten for its pool
+            host: String::new(),
+            head,
+            mode: PoolMode::Proxy,
             body_prefix,
+            body_length,
             is_connect,
+            upgrade,
+            awaits_continue,
+            hints,
+            wants_json,
         })
     }
 
-    /// Reads HTTP message (header + initial body) from stream with timeout
+    /// Answers `Expect: 100-continue` itself when the body is small enough to keep for
+    /// retries, then reads the whole body
+    ///
+    /// Larger and chunked bodies keep the header: the client then waits for an upstream
+    /// to accept the request, and the body goes straight into that upstream's tunnel.
+    async fn buffer_expected_body(
+        &self,
+        downstream: &mut Stream,
+        initial: &mut InitialRequest,
+    ) -> Result<()> {
+        if !initial.awaits_continue {
+            return Ok(());
+        }
+        let Some(length) = initial
+            .head
+            .headers
+            .get_str("content-length")
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .filter(|length| *length <= self.retries.buffer_body_bytes)
+        else {
+            return Ok(());
+        };
+
+        initial.head.headers.remove("expect");
+        initial.awaits_continue = false;
+        let length = length as usize;
+        if initial.body_prefix.len() >= length {
+            return Ok(());
+        }
+
+        downstream
+            .write_all(&ResponseHead::new(100, "Continue").to_bytes())
+            .await
+            .context("failed to write 100 Continue")?;
+        downstream
+            .flush()
+            .await
+            .context("failed to flush 100 Continue")?;
+
+        let mut chunk = [0u8; 4096];
+        while initial.body_prefix.len() < length {
+            let wanted = (length - initial.body_prefix.len()).min(chunk.len());
+            let n = timeout(
+                self.timeouts.client_header(),
+                downstream.read(&mut chunk[..wanted]),
+            )
+            .await
+            .context("timeout while reading request body")??;
+            if n == 0 {
+                bail!("connection closed while reading request body");
+            }
+            initial.body_prefix.extend_from_slice(&chunk[..n]);
+        }
+        debug!("Buffered {length}-byte request body after answering 100 Continue");
+        Ok(())
+    }
+
+    /// Returns true if the request's `Accept` header asks for JSON
+    fn accepts_json(head: &RequestHead) -> bool {
+        head.headers.get_all("accept").any(|value| {
+            String::from_utf8_lossy(value)
+                .to_ascii_lowercase()
+                .contains("application/json")
+        })
+    }
+
+    /// Removes `X-Proxywar-*` session hint headers and geo-token credentials from the request
+    fn take_session_hints(head: &mut RequestHead) -> Result<SessionHints> {
+        let mut hints = SessionHints::default();
+        for (name, value) in head.headers.iter() {
+            if SessionHints::is_hint_header(name) {
+                hints.set(name, &String::from_utf8_lossy(value))?;
+            }
+        }
+
+        head.headers.retain(|name, value| {
+            if SessionHints::is_hint_header(name) {
+                return false;
+            }
+            // Credentials carrying geo tokens are meant for proxywar, not the upstream
+            !(name.eq_ignore_ascii_case("proxy-authorization")
+                && Self::basic_auth_username(&String::from_utf8_lossy(value))
+                    .is_some_and(|username| hints.apply_username_tokens(&username)))
+        });
+
+        Ok(hints)
+    }
+
+    /// Returns the username of a `Basic` credentials header value
+    fn basic_auth_username(value: &str) -> Option<String> {
+        let (scheme, encoded) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = BASE64_ENGINE.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, _) = decoded.split_once(':')?;
+        Some(username.to_string())
+    }
+
+    /// Reads HTTP message (header + initial body) from stream with timeout, starting
+    /// with bytes already read past a previous message
     async fn read_http_message(
         stream: &mut Stream,
+        mut buffer: Vec<u8>,
         limit: usize,
         read_timeout: Duration,
     ) -> Result<(Vec<u8>, Vec<u8>)> {
-        let mut buffer = Vec::with_capacity(1024);
         let mut chunk = [0u8; 4096];
 
         loop {
+            if let Some(pos) = Self::find_header_end(&buffer) {
+                let header_end = pos + 4;
+                let body = buffer.split_off(header_end);
+                return Ok((buffer, body));
+            }
+
             let n = timeout(read_timeout, stream.read(&mut chunk))
                 .await
                 .context("timeout while reading message")?? as usize;
@@ -217,12 +1090,6 @@ impl ForwardProxy {
             if buffer.len() > limit {
                 bail!("message header exceeded {limit} bytes");
             }
-
-            if let Some(pos) = Self::find_header_end(&buffer) {
-                let header_end = pos + 4;
-                let body = buffer.split_off(header_end);
-                return Ok((buffer, body));
-            }
         }
     }
 
@@ -231,78 +1098,22 @@ impl ForwardProxy {
         buf.windows(4).position(|window| window == b"\r\n\r\n")
     }
 
-    /// Returns true if request is HTTP CONNECT method
-    fn is_connect_request(header: &[u8]) -> Result<bool> {
-        let text = std::str::from_utf8(header).context("request header is not valid utf-8")?;
-        let mut lines = text.split("\r\n");
-        let request_line = lines.next().unwrap_or_default();
-        let method = request_line.split_whitespace().next().unwrap_or_default();
-        Ok(method.eq_ignore_ascii_case("CONNECT"))
-    }
-
-    /// Adds Proxy-Authorization header if auth is provided
-    fn build_request_header(original: &[u8], auth: Option<&str>) -> Result<Vec<u8>> {
-        let Some(auth_value) = auth else {
-            return Ok(original.to_vec());
-        };
-
-        if original.len() < 4 || &original[original.len() - 4..] != b"\r\n\r\n" {
-            bail!("malformed HTTP header");
+    /// Serializes the request head, adding Proxy-Authorization if auth is provided
+    fn build_request_header(head: &RequestHead, auth: Option<&str>) -> Vec<u8> {
+        match auth {
+            Some(auth_value) if !head.headers.contains("proxy-authorization") => {
+                let mut head = head.clone();
+                head.headers.append("Proxy-Authorization", auth_value);
+                head.to_bytes()
+            }
+            _ => head.to_bytes(),
         }
+    }
 
-        let header_without_blank = &original[..original.len() - 4];
-        let header_text = std::str::from_utf8(header_without_blank)
-            .context("request header is not valid utf-8")?;
-
-        if header_text.lines().any(|line| {
-            line.trim_start()
-                .to_ascii_lowercase()
-                .starts_with("proxy-authorization:")
-        }) {
-            return Ok(original.to_vec());
-        }
-
-        const PROXY_AUTH_PREFIX: &str = "Proxy-Authorization: ";
-
-        let mut new_header =
-            Vec::with_capacity(original.len() + PROXY_AUTH_PREFIX.len() + auth_value.len() + 4);
-        new_header.extend_from_slice(header_without_blank);
-        new_header.extend_from_slice(b"\r\n");
-        new_header.extend_from_slice(PROXY_AUTH_PREFIX.as_bytes());
-        new_header.extend_from_slice(auth_value.as_bytes());
-        new_header.extend_from_slice(b"\r\n\r\n");
-        Ok(new_header)
-    }
-
-    /// Parses HTTP status code from response header
-    fn parse_status_code(header: &[u8]) -> Result<u16> {
-        let text = std::str::from_utf8(header).context("response header is not valid utf-8")?;
-        let mut lines = text.split("\r\n");
-        let status_line = lines
-            .next()
-            .ok_or_else(|| anyhow!("empty response header"))?;
-        let mut parts = status_line.split_whitespace();
-        let _ = parts
-            .next()
-            .ok_or_else(|| anyhow!("missing HTTP version"))?;
-        let status = parts
-            .next()
-            .ok_or_else(|| anyhow!("missing status code"))?
-            .parse()
-            .context("invalid status code")?;
-        Ok(status)
-    }
-
-    /// Sends 503 Service Unavailable to client
-    async fn respond_service_unavailable(stream: &mut Stream) -> Result<()> {
-        const BODY: &str = "Service Unavailable";
-        let response = format!(
-            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: {}\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n{}",
-            BODY.len(),
-            BODY
-        );
+    /// Sends an error response generated by proxywar to the client
+    async fn respond_error(stream: &mut Stream, response: &ErrorResponse) -> Result<()> {
         stream
-            .write_all(response.as_bytes())
+            .write_all(&response.to_bytes())
             .await
             .context("failed to write fallback response")?;
         stream
@@ -319,24 +1130,56 @@ impl ForwardProxy {
         metadata: &ProxyMetadata,
         initial: &InitialRequest,
     ) -> Result<AttemptOutcome> {
+        let timeouts = self.upstream_timeouts(&initial.hints);
+        let is_tls = metadata.scheme.eq_ignore_ascii_case("https");
         let mut peer = BasicPeer::new(backend_addr);
-        if metadata.scheme.eq_ignore_ascii_case("https") {
+        if is_tls {
+            // A non-empty SNI makes the connector wrap the stream in TLS
             peer.sni = metadata.host.clone();
         }
         if let Some(opts) = peer.get_mut_peer_options() {
-            opts.verify_cert = false;
-            opts.verify_hostname = false;
+            opts.verify_cert = metadata.tls.verify();
+            opts.verify_hostname = metadata.tls.verify();
+            opts.ca = metadata.tls.ca.clone();
             opts.set_http_version(1, 1);
+            opts.connection_timeout = Some(timeouts.connect);
+            // TLS with the proxy counts towards the handshake
+            opts.total_connection_timeout = Some(timeouts.connect + timeouts.handshake);
         }
 
         let (mut upstream, _reused) = self
             .connector
             .get_stream(&peer)
             .await
+            .map_err(|err| {
+                let timed_out = matches!(
+                    err.etype(),
+                    ErrorType::ConnectTimedout | ErrorType::TLSHandshakeTimedout
+                );
+                let err = anyhow::Error::new(err);
+                if timed_out {
+                    err.context(TimedOut)
+                } else {
+                    err
+                }
+            })
             .with_context(|| format!("failed to connect to upstream {backend_addr}"))?;
 
-        let auth_header = metadata.basic_auth_header();
-        let request_header = Self::build_request_header(&initial.header, auth_header.as_deref())?;
+        if is_tls && !metadata.tls.pins.is_empty() {
+            let digest = upstream
+                .get_ssl_digest()
+                .ok_or_else(|| anyhow!("no TLS session established with {backend_addr}"))?;
+            if !metadata.tls.matches_pin(&digest.cert_digest) {
+                bail!("certificate of upstream {backend_addr} does not match any pinned fingerprint");
+            }
+        }
+
+        // Origin servers get no proxy credentials
+        let auth_header = match initial.mode {
+            PoolMode::Proxy => metadata.basic_auth_header(&initial.hints),
+            PoolMode::Origin => None,
+        };
+        let request_header = Self::build_request_header(&initial.head, auth_header.as_deref());
 
         debug!("Sending request to {backend_addr}, header length: {}", request_header.len());
         debug!("Request header: {}", String::from_utf8_lossy(&request_header));
@@ -356,47 +1199,393 @@ impl ForwardProxy {
             .await
             .with_context(|| format!("failed to flush request to {backend_addr}"))?;
 
-        let (response_header, response_body_prefix) = match Self::read_http_message(
-            &mut upstream,
-            Self::HEADER_LIMIT,
-            Self::response_timeout(),
-        )
-        .await
-        {
-            Ok(data) => data,
-            Err(err) => {
-                return Err(err.context(format!(
-                    "failed to read response header from {backend_addr}"
-                )));
-            }
+        // A CONNECT reply comes from the proxy itself; other responses from the target
+        let response_timeout = if initial.is_connect {
+            timeouts.connect_reply
+        } else {
+            timeouts.first_byte
         };
+        let mut interim = Vec::new();
+        let mut buffered = Vec::new();
+        let (response, response_body_prefix) = loop {
+            let wait = if initial.awaits_continue {
+                response_timeout.min(self.timeouts.continue_wait())
+            } else {
+                response_timeout
+            };
+            let (response_header, rest) = match Self::read_http_message(
+                &mut upstream,
+                buffered,
+                Self::HEADER_LIMIT,
+                wait,
+            )
+            .await
+            {
+                Ok(data) => data,
+                // Upstreams that ignore Expect wait for the body; let the client send it
+                Err(err) if initial.awaits_continue && is_timeout(&err) => {
+                    debug!(
+                        "No 100 Continue from {backend_addr} within {wait:?}; releasing the body"
+                    );
+                    break (ResponseHead::new(100, "Continue"), Vec::new());
+                }
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "failed to read response header from {backend_addr}"
+                    )));
+                }
+            };
 
-        let status = Self::parse_status_code(&response_header)
-            .with_context(|| format!("failed to parse response status from {backend_addr}"))?;
+            let response = ResponseHead::parse(&response_header)
+                .with_context(|| format!("failed to parse response header from {backend_addr}"))?;
+            match response.status {
+                // The upstream accepted the request; the client's body goes through the tunnel
+                100 if initial.awaits_continue => break (response, rest),
+                // Not asked for, or proxywar already answered the client's Expect itself
+                100 => {}
+                // Other interim responses reach HTTP/1.1 clients ahead of the final one
+                102..=199 if initial.head.version >= 1 => {
+                    interim.extend_from_slice(&response.to_bytes());
+                    if interim.len() > Self::HEADER_LIMIT {
+                        bail!(
+                            "interim responses from {backend_addr} exceeded {} bytes",
+                            Self::HEADER_LIMIT
+                        );
+                    }
+                }
+                102..=199 => {}
+                _ => break (response, rest),
+            }
+            buffered = rest;
+        };
+        let status = response.status;
 
-        // Ban backends that return auth failure codes
-        if matches!(status, 407 | 402 | 511) {
-            self.banned.insert(backend_addr.to_string());
-            return Ok(AttemptOutcome::Retry {
-                status_code: status,
-                banned_count: self.banned.len(),
-            });
+        if let Some(outcome) =
+            self.judge_final_response(backend_addr, metadata, initial, &response)?
+        {
+            return Ok(outcome);
         }
 
         Ok(AttemptOutcome::Success {
             upstream,
-            response_header,
+            interim,
+            response,
             response_body_prefix,
             status_code: status,
+            body_sent: 0,
         })
     }
+
+    /// Checks a final response for a bad protocol switch and bans proxies that fail auth
+    ///
+    /// Returns the outcome of an attempt to retry elsewhere, or None when the response
+    /// goes to the client.
+    fn judge_final_response(
+        &self,
+        backend_addr: &str,
+        metadata: &ProxyMetadata,
+        initial: &InitialRequest,
+        response: &ResponseHead,
+    ) -> Result<Option<AttemptOutcome>> {
+        let status = response.status;
+
+        // After 101 both sides speak the new protocol and the connection becomes a tunnel
+        if status == 101 {
+            let Some(requested) = &initial.upgrade else {
+                return Err(anyhow!(
+                    "upstream {backend_addr} switched protocols without an upgrade request"
+                )
+                .context(ProtocolViolation));
+            };
+            if !rewrite::is_upgrade(&response.headers) {
+                return Err(anyhow!(
+                    "101 response from {backend_addr} does not name the new protocol"
+                )
+                .context(ProtocolViolation));
+            }
+            let offered = response.headers.get_str("upgrade").unwrap_or_default();
+            if !rewrite::is_requested_upgrade(requested, &offered) {
+                return Err(anyhow!(
+                    "upstream {backend_addr} switched to {offered:?}, not the requested {requested:?}"
+                )
+                .context(ProtocolViolation));
+            }
+        }
+
+        // Ban backends that return auth failure codes; all addresses of the proxy share the ban
+        if matches!(status, 407 | 402 | 511) {
+            self.state.ban(&metadata.id);
+            return Ok(Some(AttemptOutcome::Retry {
+                status_code: status,
+                banned_count: self.state.ban_count(),
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Relays the client's body after a 100 Continue until the upstream's final response
+    ///
+    /// The 100 only lets the body go to this upstream, whether the upstream sent it or
+    /// proxywar did after the continue wait; the final response decides the attempt.
+    /// The outer error is a failure on the client's side.
+    async fn finish_continue(
+        &self,
+        downstream: &mut Stream,
+        candidate: &Candidate,
+        mut upstream: Stream,
+        interim: Vec<u8>,
+        mut buffered: Vec<u8>,
+        initial: &InitialRequest,
+    ) -> Result<Result<AttemptOutcome>> {
+        let backend_addr = candidate.addr.as_str();
+        downstream
+            .write_all(&interim)
+            .await
+            .context("failed to forward 100 Continue")?;
+        downstream
+            .flush()
+            .await
+            .context("failed to flush 100 Continue")?;
+
+        // Uploads take a while, so the wait for the response restarts with every chunk
+        let wait = self.upstream_timeouts(&initial.hints).first_byte;
+        let mut deadline = Instant::now() + wait;
+        let mut body_sent = 0u64;
+        // Bytes past the body are left unread, like in the tunnel that follows
+        let mut body_left = initial
+            .body_length
+            .map(|length| length.saturating_sub(initial.body_prefix.len() as u64));
+        let mut client_done = body_left == Some(0);
+        let mut body = [0u8; 4096];
+        let mut reply = [0u8; 4096];
+        let (response, response_body_prefix) = loop {
+            if let Some(pos) = Self::find_header_end(&buffered) {
+                let rest = buffered.split_off(pos + 4);
+                let response = match ResponseHead::parse(&buffered) {
+                    Ok(response) => response,
+                    Err(err) => {
+                        return Ok(Err(err.context(format!(
+                            "failed to parse response header from {backend_addr}"
+                        ))));
+                    }
+                };
+                match response.status {
+                    100 => {}
+                    // The client already takes 1xx responses, having been sent a 100
+                    102..=199 => {
+                        downstream
+                            .write_all(&response.to_bytes())
+                            .await
+                            .context("failed to forward interim responses")?;
+                    }
+                    _ => break (response, rest),
+                }
+                buffered = rest;
+                continue;
+            }
+            if buffered.len() > Self::HEADER_LIMIT {
+                return Ok(Err(anyhow!(
+                    "response header from {backend_addr} exceeded {} bytes",
+                    Self::HEADER_LIMIT
+                )));
+            }
+
+            let wanted = body_left.map_or(body.len(), |left| left.min(body.len() as u64) as usize);
+            tokio::select! {
+                read = downstream.read(&mut body[..wanted]), if !client_done => {
+                    let n = read.context("failed to read request body")?;
+                    if n == 0 {
+                        client_done = true;
+                        continue;
+                    }
+                    if let Some(left) = &mut body_left {
+                        *left -= n as u64;
+                        client_done = *left == 0;
+                    }
+                    let sent = timeout(wait, async {
+                        upstream.write_all(&body[..n]).await?;
+                        upstream.flush().await
+                    })
+                    .await;
+                    match sent {
+                        Ok(Ok(())) => {}
+                        Ok(Err(err)) => {
+                            return Ok(Err(anyhow::Error::new(err)
+                                .context(format!("failed to send request body to {backend_addr}"))));
+                        }
+                        Err(_) => {
+                            return Ok(Err(anyhow!(
+                                "upstream {backend_addr} stopped taking the request body"
+                            )
+                            .context(TimedOut)));
+                        }
+                    }
+                    body_sent += n as u64;
+                    deadline = Instant::now() + wait;
+                }
+                read = upstream.read(&mut reply) => match read {
+                    Ok(0) => {
+                        return Ok(Err(anyhow!(
+                            "upstream {backend_addr} closed the connection before its final response"
+                        )));
+                    }
+                    Ok(n) => buffered.extend_from_slice(&reply[..n]),
+                    Err(err) => {
+                        return Ok(Err(anyhow::Error::new(err)
+                            .context(format!("failed to read response header from {backend_addr}"))));
+                    }
+                },
+                () = sleep_until(deadline) => {
+                    return Ok(Err(anyhow!(
+                        "no final response from {backend_addr} within {wait:?}"
+                    )
+                    .context(TimedOut)));
+                }
+            }
+        };
+
+        let outcome = match self.judge_final_response(
+            backend_addr,
+            &candidate.metadata,
+            initial,
+            &response,
+        ) {
+            Ok(Some(outcome)) => outcome,
+            Ok(None) => AttemptOutcome::Success {
+                upstream,
+                // Relayed as they arrived
+                interim: Vec::new(),
+                status_code: response.status,
+                response,
+                response_body_prefix,
+                body_sent,
+            },
+            Err(err) => return Ok(Err(err)),
+        };
+        Ok(Ok(outcome))
+    }
+}
+
+/// Per-connection client information
+struct ClientContext {
+    /// User identity from a verified client certificate
+    user: Option<String>,
+    ip: Option<IpAddr>,
 }
 
 /// Initial HTTP request from client
 struct InitialRequest {
-    header: Vec<u8>,
+    head: RequestHead,
+    /// Kind of backends the request is rewritten for
+    mode: PoolMode,
+    /// Target host without port, for host-scoped header rules
+    host: String,
     body_prefix: Vec<u8>,
+    /// Length of a `Content-Length` or empty body; None for chunked bodies and CONNECT
+    body_length: Option<u64>,
     is_connect: bool,
+    /// Protocols named in `Upgrade` when the request asks to switch protocols
+    upgrade: Option<String>,
+    /// `Expect: 100-continue` is forwarded: the client holds its body until an upstream
+    /// accepts the request
+    awaits_continue: bool,
+    /// Values for provider username templates
+    hints: SessionHints,
+    /// The client accepts JSON error bodies
+    wants_json: bool,
+}
+
+impl InitialRequest {
+    /// Wraps a request proxywar sends on its own, without client hints
+    ///
+    /// Without a session hint, session-templated proxies get a fresh session per probe.
+    fn probe(head: RequestHead) -> Self {
+        Self {
+            host: head.target_host(),
+            is_connect: head.is_connect(),
+            head,
+            mode: PoolMode::Proxy,
+            body_prefix: Vec::new(),
+            body_length: None,
+            upgrade: None,
+            awaits_continue: false,
+            hints: SessionHints::default(),
+            wants_json: false,
+        }
+    }
+}
+
+/// Attempts made for one client request
+#[derive(Default)]
+struct Attempts {
+    /// Keyed by backend ID: one attempt per logical proxy, whatever its addresses
+    tried: HashSet<String>,
+    /// Finished attempts, reported when the request fails
+    log: Vec<AttemptRecord>,
+}
+
+/// Why a request stopped trying backends
+#[derive(Debug, Clone, Copy)]
+enum StopReason {
+    /// No usable backend left to try
+    Exhausted,
+    MaxAttempts,
+    Deadline,
+    RetryBudget,
+    /// The request body went to an upstream after a 100 Continue and cannot be resent
+    BodySent,
+}
+
+impl StopReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Exhausted => "exhausted",
+            Self::MaxAttempts => "max_attempts",
+            Self::Deadline => "deadline",
+            Self::RetryBudget => "retry_budget",
+            Self::BodySent => "body_sent",
+        }
+    }
+}
+
+/// Marks an attempt that failed because one of its timeouts elapsed
+#[derive(Debug)]
+struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out")
+    }
+}
+
+/// Returns true if an attempt failed on a timeout rather than a refusal or protocol error
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TimedOut>().is_some() || err.downcast_ref::<Elapsed>().is_some()
+}
+
+/// Marks an attempt whose response broke HTTP semantics, such as an unsolicited `101`
+///
+/// The proxy's connection worked, so such failures do not count against its circuit.
+#[derive(Debug)]
+struct ProtocolViolation;
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("protocol error")
+    }
+}
+
+fn is_protocol_violation(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ProtocolViolation>().is_some()
+}
+
+/// A backend claimed for one attempt
+struct Candidate {
+    id: String,
+    addr: String,
+    metadata: ProxyMetadata,
+    started: Instant,
 }
 
 /// Result of proxy attempt
@@ -404,9 +1593,13 @@ enum AttemptOutcome {
     /// Success - connection established
     Success {
         upstream: Stream,
-        response_header: Vec<u8>,
+        /// 1xx responses to relay before `response`
+        interim: Vec<u8>,
+        response: ResponseHead,
         response_body_prefix: Vec<u8>,
         status_code: u16,
+        /// Request body bytes relayed after a 100 Continue
+        body_sent: u64,
     },
     /// Retry with different backend
     Retry {
@@ -418,10 +1611,647 @@ enum AttemptOutcome {
 #[async_trait]
 impl ServerApp for ForwardProxy {
     /// Handles new client connection
-    async fn process_new(self: &Arc<Self>, io: Stream, shutdown: &ShutdownWatch) -> Option<Stream> {
-        if let Err(err) = self.handle_connection(io, shutdown).await {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut io: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let client = match self.client_context(&io) {
+            Ok(client) => client,
+            Err(err) => {
+                debug!("Rejecting client: {err:#}");
+                let response = ErrorResponse::new(ErrorReason::ClientForbidden, format!("{err:#}"));
+                let _ = Self::respond_error(&mut io, &response).await;
+                return None;
+            }
+        };
+        if let Err(err) = self.handle_connection(io, &client, shutdown).await {
             debug!("Connection failed: {err:#}");
         }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read as _, Write as _};
+    use std::thread;
+
+    use pingora_core::tls::hash::MessageDigest;
+    use pingora_core::tls::pkey::PKey;
+    use pingora_core::tls::ssl::{SslAcceptor, SslMethod};
+    use pingora_core::tls::x509::X509;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use tempfile::NamedTempFile;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{oneshot, watch};
+
+    use super::*;
+    use crate::config::{BackendLimits, DEFAULT_POOL, SelectionMode};
+    use crate::proxy_list::ListFormat;
+    use crate::rate_limit::RateLimiter;
+    use crate::upstream::{backend_for, parse_proxy_list};
+
+    /// Stand-in upstream that reads one request header and answers with `reply`
+    async fn stand_in(reply: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let _ = stream.write_all(reply.as_bytes()).await;
+                    // Keep the connection open like a tunnel would
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// Stand-in upstream for one connection that answers the first request with `reply`
+    /// and keeps the connection open, then reports everything it received
+    async fn recording_stand_in(reply: &'static str) -> (String, oneshot::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (report, received) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => head.extend_from_slice(&buf[..n]),
+                }
+            }
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            // Whatever else arrives before the proxy closes or goes quiet
+            while let Ok(Ok(n)) = timeout(Duration::from_millis(200), stream.read(&mut buf)).await {
+                if n == 0 {
+                    break;
+                }
+                head.extend_from_slice(&buf[..n]);
+            }
+            let _ = report.send(head);
+        });
+        (addr, received)
+    }
+
+    fn proxy(config: &Config) -> ForwardProxy {
+        ForwardProxy::new(
+            SimpleBackendPool::new(Vec::new(), SelectionMode::RoundRobin),
+            config,
+        )
+    }
+
+    /// Proxy whose pool holds the one upstream at `addr`
+    fn proxy_to(config: &Config, addr: &str) -> ForwardProxy {
+        let (mut proxies, _) = parse_proxy_list(&format!("http://{addr}"), ListFormat::Auto, false);
+        let metadata = proxies.remove(0);
+        let backend = backend_for(&metadata, metadata.host.parse().unwrap()).unwrap();
+        ForwardProxy::new(
+            SimpleBackendPool::new(vec![backend], SelectionMode::RoundRobin),
+            config,
+        )
+    }
+
+    /// Sends `requests` on one client connection and returns everything the client receives
+    async fn serve(proxy: &ForwardProxy, requests: &[u8]) -> String {
+        let (downstream, mut socket) = client().await;
+        socket.write_all(requests).await.unwrap();
+        let (_stop, shutdown) = watch::channel(false);
+        let client = ClientContext {
+            user: None,
+            ip: None,
+        };
+        let mut response = Vec::new();
+        let (served, read) = timeout(Duration::from_secs(5), async {
+            tokio::join!(
+                proxy.handle_connection(downstream, &client, &shutdown),
+                socket.read_to_end(&mut response),
+            )
+        })
+        .await
+        .unwrap();
+        served.unwrap();
+        read.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    fn candidate(addr: &str) -> Candidate {
+        let (mut proxies, _) = parse_proxy_list(&format!("http://{addr}"), ListFormat::Auto, false);
+        let metadata = proxies.remove(0);
+        let backend = backend_for(&metadata, metadata.host.parse().unwrap()).unwrap();
+        Candidate {
+            id: metadata.id.clone(),
+            addr: backend.addr.to_string(),
+            metadata,
+            started: Instant::now(),
+        }
+    }
+
+    fn request(raw: &str) -> InitialRequest {
+        let mut initial =
+            ForwardProxy::parse_initial_request(raw.as_bytes().to_vec(), Vec::new()).unwrap();
+        rewrite::for_upstream_proxy(&mut initial.head).unwrap();
+        initial
+    }
+
+    /// Runs one attempt and records it like the retry loop does
+    async fn attempt(
+        proxy: &ForwardProxy,
+        candidate: &Candidate,
+        initial: &InitialRequest,
+    ) -> (Result<AttemptOutcome>, Attempts) {
+        let result = proxy
+            .try_proxy_once(&candidate.addr, &candidate.metadata, initial)
+            .await;
+        let mut attempts = Attempts::default();
+        proxy.record_attempt(candidate, &result, &mut attempts);
+        (result, attempts)
+    }
+
+    /// Returns the proxy's end of a client connection and the client's socket
+    async fn client() -> (Stream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = BasicPeer::new(&listener.local_addr().unwrap().to_string());
+        let (downstream, _) = TransportConnector::new(None)
+            .get_stream(&peer)
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        (downstream, socket)
+    }
+
+    /// Runs an attempt through the continue phase like the retry loop does
+    async fn attempt_with_body(
+        proxy: &ForwardProxy,
+        candidate: &Candidate,
+        initial: &InitialRequest,
+        downstream: &mut Stream,
+    ) -> (Result<AttemptOutcome>, Attempts) {
+        let result = match proxy
+            .try_proxy_once(&candidate.addr, &candidate.metadata, initial)
+            .await
+        {
+            Ok(AttemptOutcome::Success {
+                status_code: 100,
+                upstream,
+                mut interim,
+                response,
+                response_body_prefix,
+                ..
+            }) => {
+                interim.extend_from_slice(&response.to_bytes());
+                proxy
+                    .finish_continue(
+                        downstream,
+                        candidate,
+                        upstream,
+                        interim,
+                        response_body_prefix,
+                        initial,
+                    )
+                    .await
+                    .unwrap()
+            }
+            result => result,
+        };
+        let mut attempts = Attempts::default();
+        proxy.record_attempt(candidate, &result, &mut attempts);
+        (result, attempts)
+    }
+
+    const TLS_HOST: &str = "proxy.test";
+
+    struct Ca {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            // The default name is shared with leaf certificates, which would look self-issued
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "proxywar test CA");
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        /// Writes the CA certificate to a PEM file for the `ca` option, removed on drop
+        fn bundle(&self) -> NamedTempFile {
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(self.cert.pem().as_bytes()).unwrap();
+            file
+        }
+    }
+
+    /// TLS stand-in for an HTTPS proxy whose certificate for `TLS_HOST` is issued by `ca`
+    struct TlsStandIn {
+        port: u16,
+        cert_digest: Vec<u8>,
+    }
+
+    impl TlsStandIn {
+        fn start(ca: &Ca) -> Self {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![TLS_HOST.to_string()])
+                .unwrap()
+                .signed_by(&key, &ca.cert, &ca.key)
+                .unwrap();
+
+            let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+            acceptor
+                .set_certificate(&X509::from_der(cert.der()).unwrap())
+                .unwrap();
+            acceptor
+                .set_private_key(&PKey::private_key_from_der(&key.serialize_der()).unwrap())
+                .unwrap();
+            let acceptor = acceptor.build();
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    // Failed handshakes are what some tests expect
+                    let Ok(mut tls) = acceptor.accept(stream) else {
+                        continue;
+                    };
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match tls.read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let _ = tls.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n");
+                }
+            });
+
+            let cert_digest = X509::from_der(cert.der())
+                .unwrap()
+                .digest(MessageDigest::sha256())
+                .unwrap()
+                .to_vec();
+            Self { port, cert_digest }
+        }
+
+        fn fingerprint(&self) -> String {
+            self.cert_digest
+                .iter()
+                .map(|b| format!("{b:02X}"))
+                .collect::<Vec<_>>()
+                .join(":")
+        }
+
+        /// Sends a CONNECT through the stand-in, reached as `host` with the URL's TLS options
+        async fn connect(&self, host: &str, options: &str) -> Result<AttemptOutcome> {
+            let (mut proxies, _) = parse_proxy_list(
+                &format!("https://{host}:{}?{options}", self.port),
+                ListFormat::Auto,
+                false,
+            );
+            let metadata = proxies.remove(0);
+            let initial =
+                request("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+            proxy(&Config::default())
+                .try_proxy_once(&format!("127.0.0.1:{}", self.port), &metadata, &initial)
+                .await
+        }
+    }
+
+    fn strict_circuits() -> Config {
+        let mut config = Config::default();
+        config.circuit_breaker.consecutive_failures = 1;
+        config
+    }
+
+    #[test]
+    fn skipped_backend_gets_its_rate_limit_slot_back() {
+        let (mut proxies, _) = parse_proxy_list("http://127.0.0.1:3128", ListFormat::Auto, false);
+        let mut metadata = proxies.remove(0);
+        metadata.limiter = Some(RateLimiter::new(BackendLimits {
+            requests_per_sec: Some(1.0),
+            burst: Some(1),
+            window_requests: None,
+            window_secs: 60,
+        }));
+        let backend = backend_for(&metadata, metadata.host.parse().unwrap()).unwrap();
+        let proxy = ForwardProxy::new(
+            SimpleBackendPool::new(vec![backend], SelectionMode::RoundRobin),
+            &Config::default(),
+        );
+        proxy.state.ban(&metadata.id);
+
+        // A slot spent on the banned backend would leave the second selection limited
+        for _ in 0..2 {
+            let Some(Selected::Backend(backend)) =
+                proxy.pool.select(&SessionHints::default(), |_| false)
+            else {
+                panic!("backend was rate limited");
+            };
+            assert!(proxy.claim(&backend, &mut HashSet::new()).is_none());
+        }
+    }
+
+    const SWITCH_TO_WEBSOCKET: &str =
+        "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+
+    #[tokio::test]
+    async fn unsolicited_switch_is_a_protocol_error_without_circuit_penalty() {
+        let proxy = proxy(&strict_circuits());
+        let candidate = candidate(&stand_in(SWITCH_TO_WEBSOCKET).await);
+        let initial = request("GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n");
+
+        let (result, attempts) = attempt(&proxy, &candidate, &initial).await;
+        let err = result.err().unwrap();
+        assert!(is_protocol_violation(&err), "{err:#}");
+        assert_eq!(attempts.log[0].error, Some("protocol_error"));
+        assert!(!proxy.circuits.is_blocked(&candidate.id));
+    }
+
+    #[tokio::test]
+    async fn switch_to_another_protocol_is_rejected() {
+        let proxy = proxy(&strict_circuits());
+        let candidate = candidate(&stand_in(SWITCH_TO_WEBSOCKET).await);
+        let initial = request(
+            "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nConnection: upgrade\r\nUpgrade: h2c\r\n\r\n",
+        );
+
+        let (result, _) = attempt(&proxy, &candidate, &initial).await;
+        assert!(is_protocol_violation(&result.err().unwrap()));
+        assert!(!proxy.circuits.is_blocked(&candidate.id));
+    }
+
+    #[tokio::test]
+    async fn requested_switch_succeeds() {
+        let proxy = proxy(&strict_circuits());
+        let candidate = candidate(&stand_in(SWITCH_TO_WEBSOCKET).await);
+        let initial = request(
+            "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: WebSocket\r\n\r\n",
+        );
+
+        let (result, _) = attempt(&proxy, &candidate, &initial).await;
+        assert!(matches!(
+            result,
+            Ok(AttemptOutcome::Success {
+                status_code: 101,
+                ..
+            })
+        ));
+    }
+
+    const UPLOAD: &str = "PUT http://example.com/upload HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n";
+
+    #[tokio::test]
+    async fn auth_failure_after_continue_bans_the_proxy() {
+        let proxy = proxy(&strict_circuits());
+        let candidate = candidate(
+            &stand_in(
+                "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+            )
+            .await,
+        );
+        let initial = request(UPLOAD);
+        let (mut downstream, mut socket) = client().await;
+        socket.write_all(b"hello").await.unwrap();
+
+        let (result, attempts) =
+            attempt_with_body(&proxy, &candidate, &initial, &mut downstream).await;
+        assert!(matches!(
+            result,
+            Ok(AttemptOutcome::Retry {
+                status_code: 407,
+                ..
+            })
+        ));
+        assert_eq!(attempts.log[0].status, Some(407));
+        assert!(proxy.state.is_banned(&candidate.id));
+        assert!(!proxy.circuits.is_blocked(&candidate.id));
+
+        let mut relayed = [0; 25];
+        socket.read_exact(&mut relayed).await.unwrap();
+        assert_eq!(&relayed, b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn final_response_after_continue_decides_the_attempt() {
+        let proxy = proxy(&strict_circuits());
+        let candidate =
+            candidate(&stand_in("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\n\r\n").await);
+        let initial = request(UPLOAD);
+        let (mut downstream, _socket) = client().await;
+
+        let (result, _) = attempt_with_body(&proxy, &candidate, &initial, &mut downstream).await;
+        assert!(matches!(
+            result,
+            Ok(AttemptOutcome::Success {
+                status_code: 201,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn released_body_without_a_response_is_a_failure() {
+        let mut config = strict_circuits();
+        config.timeouts.continue_ms = 50;
+        config.timeouts.first_byte_ms = 200;
+        let proxy = proxy(&config);
+        let candidate = candidate(&stand_in("").await);
+        let initial = request(UPLOAD);
+        let (mut downstream, _socket) = client().await;
+
+        let (result, attempts) =
+            attempt_with_body(&proxy, &candidate, &initial, &mut downstream).await;
+        assert!(is_timeout(&result.err().unwrap()));
+        assert_eq!(attempts.log[0].error, Some("timeout"));
+        assert!(proxy.circuits.is_blocked(&candidate.id));
+    }
+
+    #[tokio::test]
+    async fn pipelined_request_is_not_forwarded() {
+        let (addr, received) =
+            recording_stand_in("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let proxy = proxy_to(&Config::default(), &addr);
+
+        let response = serve(
+            &proxy,
+            b"POST http://example.com/a HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello\
+              GET http://example.com/b HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\nConnection: close\r\n"), "{response}");
+
+        let received = String::from_utf8(received.await.unwrap()).unwrap();
+        assert!(
+            received.starts_with("POST http://example.com/a "),
+            "{received}"
+        );
+        assert!(received.contains("\r\nConnection: close\r\n"), "{received}");
+        assert!(received.ends_with("\r\n\r\nhello"), "{received}");
+    }
+
+    #[tokio::test]
+    async fn origin_gets_one_rewritten_request_per_connection() {
+        let (addr, received) =
+            recording_stand_in("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        let mut config = Config::default();
+        let origin = PoolConfig {
+            mode: PoolMode::Origin,
+            ..Default::default()
+        };
+        config.pools.insert(DEFAULT_POOL.to_string(), origin);
+        let proxy = proxy_to(&config, &addr);
+
+        serve(
+            &proxy,
+            b"GET http://example.com/a HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic eA==\r\n\r\n\
+              GET http://example.com/b HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic eA==\r\n\r\n",
+        )
+        .await;
+
+        let received = String::from_utf8(received.await.unwrap()).unwrap();
+        assert!(received.starts_with("GET /a HTTP/1.1\r\n"), "{received}");
+        assert!(received.contains("\r\nConnection: close\r\n"), "{received}");
+        assert!(!received.contains("Proxy-Authorization"), "{received}");
+        assert!(!received.contains("/b"), "{received}");
+    }
+
+    #[tokio::test]
+    async fn https_proxy_is_verified_against_custom_ca() {
+        let ca = Ca::new();
+        let proxy = TlsStandIn::start(&ca);
+        let bundle = ca.bundle();
+
+        let outcome = proxy
+            .connect(TLS_HOST, &format!("ca={}", bundle.path().display()))
+            .await
+            .unwrap();
+        let AttemptOutcome::Success {
+            upstream,
+            status_code: 200,
+            ..
+        } = outcome
+        else {
+            panic!("CONNECT through the TLS stand-in was not established");
+        };
+        // The connector wrapped the upstream in TLS with the stand-in's certificate
+        let digest = upstream.get_ssl_digest().unwrap();
+        assert_eq!(digest.cert_digest, proxy.cert_digest);
+    }
+
+    #[tokio::test]
+    async fn https_proxy_from_other_ca_is_rejected() {
+        let proxy = TlsStandIn::start(&Ca::new());
+        let bundle = Ca::new().bundle();
+        let options = format!("ca={}", bundle.path().display());
+        assert!(proxy.connect(TLS_HOST, &options).await.is_err());
+        // Without a `ca` option the system roots do not know the stand-in either
+        assert!(proxy.connect(TLS_HOST, "").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn https_proxy_name_must_match_its_certificate() {
+        let ca = Ca::new();
+        let proxy = TlsStandIn::start(&ca);
+        let bundle = ca.bundle();
+        let options = format!("ca={}", bundle.path().display());
+        assert!(proxy.connect("other.test", &options).await.is_err());
+        assert!(proxy.connect(TLS_HOST, &options).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn https_proxy_pin_is_checked_on_top_of_verification() {
+        let ca = Ca::new();
+        let proxy = TlsStandIn::start(&ca);
+        let bundle = ca.bundle();
+        let ca_option = format!("ca={}", bundle.path().display());
+
+        let pinned = format!("{ca_option}&pin={}", proxy.fingerprint());
+        assert!(proxy.connect(TLS_HOST, &pinned).await.is_ok());
+
+        let mismatched = format!("{ca_option}&pin=sha256:{}", "ab".repeat(32));
+        let err = proxy.connect(TLS_HOST, &mismatched).await.err().unwrap();
+        assert!(err.to_string().contains("pinned"), "{err}");
+
+        // A matching pin does not make an untrusted chain acceptable
+        let untrusted = format!("pin={}", proxy.fingerprint());
+        assert!(proxy.connect(TLS_HOST, &untrusted).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn insecure_https_proxy_skips_verification_but_keeps_pins() {
+        let proxy = TlsStandIn::start(&Ca::new());
+        assert!(proxy.connect("other.test", "insecure=true").await.is_ok());
+
+        let pinned = format!("insecure=true&pin={}", proxy.fingerprint());
+        assert!(proxy.connect(TLS_HOST, &pinned).await.is_ok());
+        let mismatched = format!("insecure=true&pin={}", "00".repeat(32));
+        assert!(proxy.connect(TLS_HOST, &mismatched).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn request_after_shutdown_is_not_forwarded() {
+        let (addr, received) =
+            recording_stand_in("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let proxy = proxy_to(&Config::default(), &addr);
+        let (downstream, mut socket) = client().await;
+        let (stop, shutdown) = watch::channel(false);
+        let client = ClientContext {
+            user: None,
+            ip: None,
+        };
+
+        let exchange = async {
+            socket
+                .write_all(b"GET http://example.com/a HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            let mut buf = [0; 256];
+            while !response.ends_with(b"ok") {
+                let n = socket.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before the response");
+                response.extend_from_slice(&buf[..n]);
+            }
+            stop.send(true).unwrap();
+            // The connection may already be gone
+            let _ = socket
+                .write_all(b"GET http://example.com/b HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await;
+            let _ = socket.read_to_end(&mut response).await;
+        };
+        let (served, ()) = timeout(Duration::from_secs(5), async {
+            tokio::join!(
+                proxy.handle_connection(downstream, &client, &shutdown),
+                exchange
+            )
+        })
+        .await
+        .unwrap();
+        served.unwrap();
+
+        let received = String::from_utf8(received.await.unwrap()).unwrap();
+        assert!(
+            received.starts_with("GET http://example.com/a "),
+            "{received}"
+        );
+        assert!(!received.contains("/b"), "{received}");
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::BackendLimits;
+
+/// Request limits for one proxy, shared by all its addresses
+///
+/// Combines a token bucket (`requests_per_sec` with `burst`) and a rolling window
+/// (`window_requests` per `window_secs`). A request is admitted only when both allow it.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limits: BackendLimits,
+    state: Arc<Mutex<LimiterState>>,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    tokens: f64,
+    refilled: Instant,
+    /// Admission times inside the rolling window
+    window: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: BackendLimits) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LimiterState {
+                tokens: limits.burst(),
+                refilled: Instant::now(),
+                window: VecDeque::new(),
+            })),
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> &BackendLimits {
+        &self.limits
+    }
+
+    /// Admits one request, or returns how long until one would be admitted
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut wait = Duration::ZERO;
+
+        if let Some(rate) = self.limits.requests_per_sec {
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(self.limits.burst());
+            state.refilled = now;
+            if state.tokens < 1.0 {
+                wait = Duration::from_secs_f64((1.0 - state.tokens) / rate);
+            }
+        }
+
+        if let Some(max) = self.limits.window_requests {
+            let window = self.limits.window();
+            while state
+                .window
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= window)
+            {
+                state.window.pop_front();
+            }
+            if state.window.len() >= max as usize
+                && let Some(oldest) = state.window.front()
+            {
+                wait = wait.max((*oldest + window).saturating_duration_since(now));
+            }
+        }
+
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        if self.limits.requests_per_sec.is_some() {
+            state.tokens -= 1.0;
+        }
+        if self.limits.window_requests.is_some() {
+            state.window.push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Gives back a request admitted by `try_acquire` that was never sent
+    pub fn refund(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if self.limits.requests_per_sec.is_some() {
+            state.tokens = (state.tokens + 1.0).min(self.limits.burst());
+        }
+        if self.limits.window_requests.is_some() {
+            state.window.pop_back();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(rate: Option<f64>, burst: Option<u32>, window: Option<u32>) -> BackendLimits {
+        BackendLimits {
+            requests_per_sec: rate,
+            burst,
+            window_requests: window,
+            window_secs: 60,
+        }
+    }
+
+    #[test]
+    fn admits_burst_then_reports_wait() {
+        let limiter = RateLimiter::new(limits(Some(2.0), Some(3), None));
+        for _ in 0..3 {
+            assert!(limiter.try_acquire().is_ok());
+        }
+        let wait = limiter.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn refills_tokens_over_time() {
+        let limiter = RateLimiter::new(limits(Some(100.0), Some(1), None));
+        assert!(limiter.try_acquire().is_ok());
+        assert!(limiter.try_acquire().is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn burst_defaults_to_one_second_of_requests() {
+        assert_eq!(limits(Some(2.5), None, None).burst(), 3.0);
+        assert_eq!(limits(Some(0.2), None, None).burst(), 1.0);
+        assert_eq!(limits(Some(5.0), Some(0), None).burst(), 1.0);
+    }
+
+    #[test]
+    fn enforces_rolling_window() {
+        let limiter = RateLimiter::new(limits(None, None, Some(2)));
+        assert!(limiter.try_acquire().is_ok());
+        assert!(limiter.try_acquire().is_ok());
+        let wait = limiter.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn rejected_requests_spend_nothing() {
+        // The window is full, so the bucket must keep its tokens
+        let limiter = RateLimiter::new(limits(Some(1.0), Some(5), Some(1)));
+        assert!(limiter.try_acquire().is_ok());
+        for _ in 0..3 {
+            assert!(limiter.try_acquire().is_err());
+        }
+        let state = limiter.state.lock().unwrap();
+        assert!(state.tokens >= 4.0 && state.tokens < 4.1);
+        assert_eq!(state.window.len(), 1);
+    }
+
+    #[test]
+    fn refund_returns_the_request() {
+        let limiter = RateLimiter::new(limits(Some(1.0), Some(1), Some(1)));
+        assert!(limiter.try_acquire().is_ok());
+        assert!(limiter.try_acquire().is_err());
+        limiter.refund();
+        assert!(limiter.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn clones_share_state() {
+        let limiter = RateLimiter::new(limits(Some(1.0), Some(1), None));
+        let other_address = limiter.clone();
+        assert!(limiter.try_acquire().is_ok());
+        assert!(other_address.try_acquire().is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use anyhow::{Result, bail};
+
+use crate::http_head::{Headers, RequestHead, ResponseHead};
+
+/// Headers that only apply to a single connection and are never forwarded (RFC 9110 7.6.1)
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Message framing is kept even when a client lists it in `Connection`
+const FRAMING: &[&str] = &["content-length", "transfer-encoding", "host"];
+
+/// Prepares a client request for an upstream HTTP proxy
+///
+/// Upstream proxies expect plain HTTP requests in absolute form, so origin-form
+/// requests (`GET /path` with a `Host` header) are rewritten using their `Host`.
+/// `Host` is then set to the URI authority, which takes precedence over a
+/// conflicting `Host` sent by the client, and hop-by-hop headers are removed.
+/// CONNECT requests keep their authority-form target. Other requests that do not
+/// upgrade get `Connection: close`: proxywar relays one exchange per connection.
+pub fn for_upstream_proxy(head: &mut RequestHead) -> Result<()> {
+    if head.is_connect() {
+        if !head.headers.contains("host") {
+            let target = head.target.clone();
+            head.headers.append("Host", target);
+        }
+    } else if head.target.starts_with('/') {
+        let Some(host) = head
+            .headers
+            .get_str("host")
+            .map(|host| host.trim().to_string())
+        else {
+            bail!("origin-form request {} has no Host header", head.target);
+        };
+        if !is_valid_authority(&host) {
+            bail!("invalid Host header {host:?}");
+        }
+        head.target = format!("http://{host}{}", head.target);
+    } else if head.target != "*" {
+        let authority = absolute_authority(&head.target)?.to_string();
+        head.headers.remove("host");
+        head.headers.append("Host", authority);
+    }
+
+    let upgrading = is_upgrade(&head.headers);
+    strip_hop_by_hop(&mut head.headers, upgrading);
+    if !head.is_connect() && !upgrading {
+        head.headers.append("Connection", "close");
+    }
+    Ok(())
+}
+
+/// Prepares a client request for an origin server or reverse-proxy target
+///
+/// Absolute-form targets are reduced to origin form (`/path?query`) and `Host` is set
+/// to their authority. Hop-by-hop headers are removed, along with `Proxy-Authorization`,
+/// which is meant for a proxy. CONNECT is refused: there is no proxy to open a tunnel.
+/// Requests that do not upgrade get `Connection: close`, as for upstream proxies.
+pub fn for_origin(head: &mut RequestHead) -> Result<()> {
+    if head.is_connect() {
+        bail!("CONNECT is not supported for origin servers");
+    }
+    if head.target.starts_with('/') {
+        if !head.headers.contains("host") {
+            bail!("origin-form request {} has no Host header", head.target);
+        }
+    } else if head.target != "*" {
+        let authority = absolute_authority(&head.target)?.to_string();
+        head.target = origin_form(&head.target);
+        head.headers.remove("host");
+        head.headers.append("Host", authority);
+    }
+
+    head.headers.remove("proxy-authorization");
+    let upgrading = is_upgrade(&head.headers);
+    strip_hop_by_hop(&mut head.headers, upgrading);
+    if !upgrading {
+        head.headers.append("Connection", "close");
+    }
+    Ok(())
+}
+
+/// Removes hop-by-hop headers from an upstream response before it is relayed
+///
+/// Unless the response opens a tunnel (`tunnel`: an accepted CONNECT or a 101), it gets
+/// `Connection: close`, since proxywar closes the connection after one exchange. So does
+/// a tunnel the upstream is about to close. A 101 keeps its `Upgrade`.
+pub fn for_client(response: &mut ResponseHead, tunnel: bool) {
+    let closing = !tunnel || connection_tokens(&response.headers).any(|token| token == "close");
+    strip_hop_by_hop(&mut response.headers, response.status == 101);
+    if closing {
+        response.headers.append("Connection", "close");
+    }
+}
+
+/// Returns true if the message asks to switch protocols (`Connection: upgrade` with `Upgrade`)
+pub fn is_upgrade(headers: &Headers) -> bool {
+    headers.contains("upgrade") && connection_tokens(headers).any(|token| token == "upgrade")
+}
+
+/// Returns true if every protocol a `101` switches to was among those the request offered
+///
+/// Both are comma-separated `Upgrade` values; protocol names compare case-insensitively.
+pub fn is_requested_upgrade(requested: &str, offered: &str) -> bool {
+    let tokens = |value: &str| -> Vec<String> {
+        value
+            .split(',')
+            .map(|token| token.trim().to_ascii_lowercase())
+            .filter(|token| !token.is_empty())
+            .collect()
+    };
+    let requested = tokens(requested);
+    let offered = tokens(offered);
+    !offered.is_empty() && offered.iter().all(|token| requested.contains(token))
+}
+
+/// Removes hop-by-hop headers and the headers named in `Connection`
+///
+/// With `keep_upgrade`, `Upgrade` and a bare `Connection: upgrade` survive so the
+/// protocol switch reaches the next hop.
+pub fn strip_hop_by_hop(headers: &mut Headers, keep_upgrade: bool) {
+    let listed: Vec<String> = connection_tokens(headers)
+        .filter(|token| !FRAMING.contains(&token.as_str()))
+        .collect();
+    headers.retain(|name, _| {
+        let name = name.to_ascii_lowercase();
+        if keep_upgrade && name == "upgrade" {
+            return true;
+        }
+        !HOP_BY_HOP.contains(&name.as_str()) && !listed.contains(&name)
+    });
+    if keep_upgrade {
+        headers.append("Connection", "upgrade");
+    }
+}
+
+/// Lowercased options listed in every `Connection` header
+fn connection_tokens(headers: &Headers) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all("connection")
+        .flat_map(|value| {
+            String::from_utf8_lossy(value)
+                .split(',')
+                .map(|token| token.trim().to_ascii_lowercase())
+                .collect::<Vec<_>>()
+        })
+        .filter(|token| !token.is_empty())
+}
+
+/// Returns the authority of an absolute-form target, without user info
+fn absolute_authority(target: &str) -> Result<&str> {
+    let Some((scheme, rest)) = target.split_once("://") else {
+        bail!("request target {target:?} is neither origin nor absolute form");
+    };
+    if !scheme.eq_ignore_ascii_case("http") {
+        bail!("unsupported scheme {scheme:?} in request target");
+    }
+
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    if !is_valid_authority(authority) {
+        bail!("invalid authority in request target {target:?}");
+    }
+    Ok(authority)
+}
+
+/// Returns the path and query of an absolute-form target, dropping any fragment
+fn origin_form(target: &str) -> String {
+    let rest = target.split_once("://").map_or(target, |(_, rest)| rest);
+    let rest = rest.split('#').next().unwrap_or_default();
+    match rest.find(['/', '?']) {
+        Some(start) if rest[start..].starts_with('/') => rest[start..].to_string(),
+        Some(start) => format!("/{}", &rest[start..]),
+        None => "/".to_string(),
+    }
+}
+
+fn is_valid_authority(authority: &str) -> bool {
+    !authority.is_empty()
+        && !authority
+            .chars()
+            .any(|c| c.is_ascii_whitespace() || c.is_ascii_control() || "/?#@".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(raw: &str) -> RequestHead {
+        RequestHead::parse(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn upstream_proxy_gets_absolute_form() {
+        let mut request = head("GET /a?b=1 HTTP/1.1\r\nHost: example.com\r\nKeep-Alive: 5\r\n\r\n");
+        for_upstream_proxy(&mut request).unwrap();
+        assert_eq!(request.target, "http://example.com/a?b=1");
+        assert!(!request.headers.contains("keep-alive"));
+    }
+
+    #[test]
+    fn upstream_proxy_requests_close_after_one_exchange() {
+        let mut request =
+            head("GET /a HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive\r\n\r\n");
+        for_upstream_proxy(&mut request).unwrap();
+        assert_eq!(
+            request.headers.get_str("connection").as_deref(),
+            Some("close")
+        );
+
+        let mut connect = head("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+        for_upstream_proxy(&mut connect).unwrap();
+        assert!(!connect.headers.contains("connection"));
+    }
+
+    #[test]
+    fn origin_gets_origin_form_and_host_from_authority() {
+        let mut request = head(
+            "GET http://user@example.com:8080/a/b?c=1#frag HTTP/1.1\r\nHost: other\r\nProxy-Authorization: Basic eA==\r\nConnection: x-hop\r\nX-Hop: 1\r\n\r\n",
+        );
+        for_origin(&mut request).unwrap();
+        assert_eq!(request.target, "/a/b?c=1");
+        let hosts: Vec<&[u8]> = request.headers.get_all("host").collect();
+        assert_eq!(hosts, [b"example.com:8080"]);
+        assert!(!request.headers.contains("proxy-authorization"));
+        assert!(!request.headers.contains("x-hop"));
+        assert_eq!(
+            request.headers.get_str("connection").as_deref(),
+            Some("close")
+        );
+    }
+
+    #[test]
+    fn origin_form_of_bare_authority() {
+        assert_eq!(origin_form("http://example.com"), "/");
+        assert_eq!(origin_form("http://example.com?q=1"), "/?q=1");
+        assert_eq!(origin_form("http://example.com/"), "/");
+    }
+
+    #[test]
+    fn origin_keeps_origin_form_and_refuses_connect() {
+        let mut request = head("GET /x HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        for_origin(&mut request).unwrap();
+        assert_eq!(request.target, "/x");
+
+        let mut request = head("GET /x HTTP/1.1\r\n\r\n");
+        assert!(for_origin(&mut request).is_err());
+        let mut connect = head("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+        assert!(for_origin(&mut connect).is_err());
+    }
+
+    #[test]
+    fn upgrade_survives_rewriting() {
+        let mut request = head(
+            "GET http://example.com/ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n",
+        );
+        for_origin(&mut request).unwrap();
+        assert_eq!(
+            request.headers.get_str("upgrade").as_deref(),
+            Some("websocket")
+        );
+        assert_eq!(
+            request.headers.get_str("connection").as_deref(),
+            Some("upgrade")
+        );
+    }
+
+    #[test]
+    fn upgrade_must_be_one_requested() {
+        assert!(is_requested_upgrade("websocket", "WebSocket"));
+        assert!(is_requested_upgrade("h2c, websocket", "websocket"));
+        assert!(!is_requested_upgrade("websocket", "h2c"));
+        assert!(!is_requested_upgrade("websocket", "websocket, h2c"));
+        assert!(!is_requested_upgrade("websocket", " "));
+    }
+
+    #[test]
+    fn client_response_loses_hop_by_hop_headers() {
+        let raw = "HTTP/1.1 200 OK\r\nConnection: close, x-hop\r\nKeep-Alive: 5\r\nX-Hop: 1\r\nTrailer: x\r\nContent-Length: 2\r\n\r\n";
+        let mut response = ResponseHead::parse(raw.as_bytes()).unwrap();
+        for_client(&mut response, true);
+        let names: Vec<&str> = response.headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["Content-Length", "Connection"]);
+        assert_eq!(
+            response.headers.get_str("connection").as_deref(),
+            Some("close")
+        );
+
+        let raw =
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        let mut response = ResponseHead::parse(raw.as_bytes()).unwrap();
+        for_client(&mut response, true);
+        assert!(is_upgrade(&response.headers));
+    }
+
+    #[test]
+    fn only_tunnels_keep_the_client_connection_open() {
+        let raw = "HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n";
+        let mut response = ResponseHead::parse(raw.as_bytes()).unwrap();
+        for_client(&mut response, false);
+        assert_eq!(
+            response.headers.get_str("connection").as_deref(),
+            Some("close")
+        );
+
+        let mut response =
+            ResponseHead::parse(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+        for_client(&mut response, true);
+        assert!(!response.headers.contains("connection"));
+    }
+}
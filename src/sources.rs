@@ -0,0 +1,530 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use tokio::time::{Instant, sleep_until};
+use tracing::{debug, error, info, warn};
+
+use crate::backend_pool::SimpleBackendPool;
+use crate::config::{DnsConfig, SourceConfig, SourceLocation};
+use crate::dns::DnsCache;
+use crate::exit_ip::ExitIp;
+use crate::rate_limit::RateLimiter;
+use crate::geo::GeoDb;
+use crate::proxy_list::{ProxyEntry, parse_entries};
+use crate::upstream::{ProxyMetadata, backend_for, build_proxies};
+
+/// Loads proxy lists from all configured sources and keeps the pool up to date
+///
+/// Hostname-based proxies expand to one backend per resolved address and are
+/// re-resolved as their DNS records expire.
+pub struct ProxySources {
+    sources: Vec<Source>,
+    pool: Arc<SimpleBackendPool>,
+    client: reqwest::Client,
+    dns: DnsCache,
+    geo: Option<Arc<GeoDb>>,
+}
+
+/// A source and its last successfully loaded proxies
+struct Source {
+    config: SourceConfig,
+    snapshot: Mutex<Option<Vec<ProxyMetadata>>>,
+}
+
+impl ProxySources {
+    const FETCH_TIMEOUT_SECS: u64 = 30;
+
+    /// Creates the source set; nothing is loaded until `refresh_all` or `start`
+    ///
+    /// The HTTP client and resolver bind to the runtime that first uses them, so
+    /// the source set must be used from a single runtime.
+    pub fn new(
+        configs: Vec<SourceConfig>,
+        dns: &DnsConfig,
+        geo: Option<Arc<GeoDb>>,
+        pool: Arc<SimpleBackendPool>,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(Self::FETCH_TIMEOUT_SECS))
+            .build()
+            .context("failed to create HTTP client for proxy sources")?;
+
+        let sources = configs
+            .into_iter()
+            .map(|config| Source {
+                config,
+                snapshot: Mutex::new(None),
+            })
+            .collect();
+
+        Ok(Self {
+            sources,
+            pool,
+            client,
+            dns: DnsCache::new(dns)?,
+            geo,
+        })
+    }
+
+    /// Loads every source once and publishes the merged list to the pool
+    pub async fn refresh_all(&self) -> Result<usize> {
+        for source in &self.sources {
+            self.refresh(source).await;
+        }
+        self.resolve().await;
+
+        let count = self.publish();
+        if count == 0 {
+            bail!("no proxies loaded from any source");
+        }
+        Ok(count)
+    }
+
+    /// Reloads one source; on failure its previous snapshot is kept
+    async fn refresh(&self, source: &Source) -> bool {
+        match self.load(&source.config).await {
+            Ok(proxies) => {
+                debug!(
+                    "Loaded {} proxies from {}",
+                    proxies.len(),
+                    source.config.location
+                );
+                *source.snapshot.lock().unwrap_or_else(|e| e.into_inner()) = Some(proxies);
+                true
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to load proxies from {}; keeping last good snapshot: {err:#}",
+                    source.config.location
+                );
+                false
+            }
+        }
+    }
+
+    /// Fetches and parses the proxies of one source
+    async fn load(&self, config: &SourceConfig) -> Result<Vec<ProxyMetadata>> {
+        let documents = match &config.location {
+            SourceLocation::Url(url) => vec![(url.clone(), self.fetch(url, config).await?)],
+            _ => {
+                let mut documents = Vec::new();
+                for path in local_files(&config.location)? {
+                    let raw = tokio::fs::read_to_string(&path)
+                        .await
+                        .with_context(|| format!("failed to read {}", path.display()))?;
+                    documents.push((path.display().to_string(), raw));
+                }
+                documents
+            }
+        };
+
+        let mut entries = Vec::new();
+        for (name, raw) in documents {
+            let (parsed, errors) = parse_entries(&raw, config.format, config.decode_credentials);
+            for error in errors {
+                warn!("Skipping invalid proxy at {name}:{}: {:#}", error.line, error.error);
+            }
+            entries.extend(parsed);
+        }
+
+        for entry in &mut entries {
+            apply_defaults(config, entry);
+        }
+
+        let (mut proxies, errors) = build_proxies(entries);
+        for error in errors {
+            warn!("Skipping proxy {}: {:#}", error.text, error.error);
+        }
+        for proxy in &mut proxies {
+            proxy.username_template = config.username_template.clone();
+            proxy.limiter = config.rate_limit.map(RateLimiter::new);
+            if let Some(pool) = &config.pool {
+                proxy.pool = pool.clone();
+            }
+        }
+
+        if proxies.is_empty() {
+            bail!("source returned no usable proxies");
+        }
+        Ok(proxies)
+    }
+
+    async fn fetch(&self, url: &str, config: &SourceConfig) -> Result<String> {
+        let mut request = self.client.get(url);
+        for (name, value) in &config.headers {
+            request = request.header(name, value);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("failed to fetch {url}"))?
+            .error_for_status()
+            .with_context(|| format!("bad response from {url}"))?;
+
+        response
+            .text()
+            .await
+            .with_context(|| format!("failed to read response from {url}"))
+    }
+
+    /// Returns the proxies of all snapshots, keeping the first listing of each endpoint
+    ///
+    /// An endpoint is a host, port and username: sources may list the same one under
+    /// different schemes or IDs.
+    fn merged(&self) -> Vec<ProxyMetadata> {
+        let mut seen = HashSet::new();
+        let mut merged = Vec::new();
+
+        for source in &self.sources {
+            let snapshot = source.snapshot.lock().unwrap_or_else(|e| e.into_inner());
+            for proxy in snapshot.iter().flatten() {
+                let endpoint = (
+                    proxy.host.to_ascii_lowercase(),
+                    proxy.port,
+                    proxy.username.clone(),
+                );
+                if seen.insert(endpoint) {
+                    merged.push(proxy.clone());
+                }
+            }
+        }
+
+        merged
+    }
+
+    /// Resolves new or expired hostnames; returns true if any address set changed
+    async fn resolve(&self) -> bool {
+        let proxies = self.merged();
+        let hosts: Vec<&str> = proxies.iter().map(|p| p.host.as_str()).collect();
+        self.dns.retain(hosts.iter().copied());
+        self.dns.refresh(hosts).await
+    }
+
+    /// Copies a proxy's metadata for one address, locating the address in the geo
+    /// database for proxies whose exit IP has not been probed yet
+    fn metadata_for(&self, proxy: &ProxyMetadata, ip: IpAddr) -> ProxyMetadata {
+        let mut metadata = proxy.clone();
+        if let Some(geo) = &self.geo
+            && (metadata.attrs.country.is_none() || metadata.attrs.city.is_none())
+        {
+            metadata.gateway_location = geo.lookup(ip);
+        }
+        metadata
+    }
+
+    /// Expands proxies into one backend per resolved address and swaps them into the pool
+    ///
+    /// Exit IPs observed for a backend carry over when the same proxy and address remain,
+    /// and rate limiter state when the same proxy keeps the same limits.
+    fn publish(&self) -> usize {
+        let current = self.pool.backends();
+        let mut previous: HashMap<(String, String), ExitIp> = HashMap::new();
+        let mut limiters: HashMap<String, RateLimiter> = HashMap::new();
+        for backend in current.iter() {
+            let Some(metadata) = backend.ext.get::<ProxyMetadata>() else {
+                continue;
+            };
+            previous.insert(
+                (metadata.id.clone(), backend.addr.to_string()),
+                metadata.exit_ip.clone(),
+            );
+            if let Some(limiter) = &metadata.limiter {
+                limiters.insert(metadata.id.clone(), limiter.clone());
+            }
+        }
+        let mut backends = Vec::new();
+
+        for mut proxy in self.merged() {
+            if let Some(limiter) = &proxy.limiter
+                && let Some(old) = limiters.get(&proxy.id)
+                && old.limits() == limiter.limits()
+            {
+                proxy.limiter = Some(old.clone());
+            }
+
+            let addrs = self.dns.addresses(&proxy.host);
+            if addrs.is_empty() {
+                warn!("No addresses for {}; proxy left out of the pool", proxy.label());
+                continue;
+            }
+
+            for ip in addrs {
+                let mut metadata = self.metadata_for(&proxy, ip);
+                let key = (metadata.id.clone(), SocketAddr::new(ip, metadata.port).to_string());
+                metadata.exit_ip = previous.remove(&key).unwrap_or_default();
+
+                match backend_for(&metadata, ip) {
+                    Ok(backend) => backends.push(backend),
+                    Err(err) => warn!("{err:#}"),
+                }
+            }
+        }
+
+        let count = backends.len();
+        self.pool.update(backends);
+        count
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ProxySources {
+    /// Loads every source, then refreshes each on its own interval and re-resolves
+    /// hostnames until shutdown
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        tokio::select! {
+            loaded = self.refresh_all() => match loaded {
+                Ok(count) => info!("Loaded {count} proxy backends"),
+                Err(err) => error!("Initial proxy load failed: {err:#}"),
+            },
+            _ = shutdown.changed() => return,
+        }
+
+        let mut next_due: Vec<Option<Instant>> = self
+            .sources
+            .iter()
+            .map(|source| source.config.refresh_interval().map(|i| Instant::now() + i))
+            .collect();
+
+        loop {
+            let wake_at = next_due
+                .iter()
+                .flatten()
+                .copied()
+                .chain(self.dns.next_expiry())
+                .min();
+
+            tokio::select! {
+                _ = sleep_until_opt(wake_at) => {}
+                _ = shutdown.changed() => return,
+            }
+
+            let now = Instant::now();
+            let mut changed = false;
+            for (source, due) in self.sources.iter().zip(next_due.iter_mut()) {
+                let Some(at) = *due else { continue };
+                if at > now {
+                    continue;
+                }
+                changed |= self.refresh(source).await;
+                *due = source.config.refresh_interval().map(|i| Instant::now() + i);
+            }
+
+            changed |= self.resolve().await;
+
+            if changed {
+                let count = self.publish();
+                info!("Proxy sources refreshed; {count} backends in pool");
+            }
+        }
+    }
+}
+
+async fn sleep_until_opt(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Lists the files behind a local source
+pub fn local_files(location: &SourceLocation) -> Result<Vec<PathBuf>> {
+    let mut files = match location {
+        SourceLocation::File(path) => return Ok(vec![path.clone()]),
+        SourceLocation::Glob(pattern) => glob::glob(pattern)
+            .with_context(|| format!("invalid glob pattern {pattern}"))?
+            .filter_map(|entry| entry.ok())
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>(),
+        SourceLocation::Dir(dir) => fs::read_dir(dir)
+            .with_context(|| format!("failed to read directory {}", dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.is_file()
+                    && !path
+                        .file_name()
+                        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+            })
+            .collect::<Vec<_>>(),
+        SourceLocation::Url(_) => Vec::new(),
+    };
+
+    if files.is_empty() {
+        bail!("{location} matched no files");
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Fills in source-level scheme, credentials, provider and tags
+fn apply_defaults(config: &SourceConfig, entry: &mut ProxyEntry) {
+    if entry.scheme.is_empty()
+        && let Some(scheme) = &config.default_scheme
+    {
+        entry.scheme = scheme.to_ascii_lowercase();
+    }
+    if entry.username.is_none() {
+        entry.username = config.username.clone();
+        entry.password = config.password.clone();
+    }
+    if entry.attrs.provider.is_none() {
+        entry.attrs.provider = config.provider.clone();
+    }
+    for tag in &config.tags {
+        if !entry.attrs.tags.contains(tag) {
+            entry.attrs.tags.push(tag.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::SelectionMode;
+
+    /// Serves the given responses to successive requests and returns the request heads seen
+    async fn stand_in(responses: Vec<String>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    head.extend_from_slice(&buf[..n]);
+                }
+                seen.lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&head).into_owned());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn sources(yaml: &str) -> (ProxySources, Arc<SimpleBackendPool>) {
+        let config: SourceConfig = serde_yaml::from_str(yaml).unwrap();
+        let pool = SimpleBackendPool::new(Vec::new(), SelectionMode::default());
+        let sources =
+            ProxySources::new(vec![config], &DnsConfig::default(), None, pool.clone()).unwrap();
+        (sources, pool)
+    }
+
+    fn ids(pool: &SimpleBackendPool) -> Vec<String> {
+        let mut ids: Vec<String> = pool
+            .backends()
+            .iter()
+            .map(crate::upstream::backend_id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn loads_http_source_with_headers_and_defaults() {
+        let (url, requests) = stand_in(vec![ok(
+            "203.0.113.7:8080\n198.51.100.2:3128:other:secret\nnot a proxy\n",
+        )])
+        .await;
+        let (sources, pool) = sources(&format!(
+            "url: {url}\nusername: user\npassword: pass\nprovider: acme\n\
+             headers:\n  Authorization: Token abc123\n"
+        ));
+
+        assert_eq!(sources.refresh_all().await.unwrap(), 2);
+        assert_eq!(
+            ids(&pool),
+            [
+                "http://other@198.51.100.2:3128",
+                "http://user@203.0.113.7:8080"
+            ]
+        );
+        let provider = pool.backends()[0]
+            .ext
+            .get::<ProxyMetadata>()
+            .and_then(|m| m.attrs.provider.clone());
+        assert_eq!(provider.as_deref(), Some("acme"));
+
+        let request = requests.lock().unwrap()[0].to_ascii_lowercase();
+        assert!(request.starts_with("get /list "), "{request}");
+        assert!(request.contains("authorization: token abc123"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn keeps_last_good_snapshot_when_source_fails() {
+        let (url, _) = stand_in(vec![
+            ok("203.0.113.7:8080\n"),
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+            ok("# nothing usable\n"),
+        ])
+        .await;
+        let (sources, pool) = sources(&format!("url: {url}\n"));
+
+        sources.refresh_all().await.unwrap();
+        let source = &sources.sources[0];
+        assert!(!sources.refresh(source).await);
+        assert!(!sources.refresh(source).await);
+        assert_eq!(sources.publish(), 1);
+        assert_eq!(ids(&pool), ["http://@203.0.113.7:8080"]);
+    }
+
+    #[tokio::test]
+    async fn merges_endpoints_listed_by_several_sources() {
+        let (first, _) = stand_in(vec![ok("203.0.113.7:8080\n")]).await;
+        let (second, _) = stand_in(vec![ok(
+            "https://203.0.113.7:8080\nHTTP://user@203.0.113.7:8080\n",
+        )])
+        .await;
+        let configs = [first, second]
+            .iter()
+            .map(|url| serde_yaml::from_str(&format!("url: {url}\n")).unwrap())
+            .collect();
+        let pool = SimpleBackendPool::new(Vec::new(), SelectionMode::default());
+        let sources =
+            ProxySources::new(configs, &DnsConfig::default(), None, pool.clone()).unwrap();
+
+        assert_eq!(sources.refresh_all().await.unwrap(), 2);
+        assert_eq!(
+            ids(&pool),
+            ["http://@203.0.113.7:8080", "http://user@203.0.113.7:8080"]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_source_loads() {
+        let (url, _) = stand_in(vec![
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        ])
+        .await;
+        let (sources, pool) = sources(&format!("url: {url}\n"));
+        assert!(sources.refresh_all().await.is_err());
+        assert!(pool.backends().is_empty());
+    }
+}
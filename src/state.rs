@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::StateConfig;
+use crate::usage::{UsageState, UsageStore};
+
+/// Runtime knowledge about backends that is worth keeping across restarts:
+/// bans, per-backend performance and sticky session bindings, all keyed by backend ID
+#[derive(Default)]
+pub struct ProxyState {
+    /// Backend ID to the unix time it was banned
+    bans: DashMap<String, u64>,
+    stats: DashMap<String, BackendStats>,
+    sessions: DashMap<String, SessionBinding>,
+}
+
+/// Performance history of one backend
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackendStats {
+    pub successes: u64,
+    pub failures: u64,
+    /// Moving average of the time to the proxy's response header
+    pub latency_ms: f64,
+    /// Unix time of the last attempt
+    pub last_used: u64,
+}
+
+/// A client session pinned to one backend so it keeps its exit IP
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SessionBinding {
+    backend: String,
+    /// Unix time the binding lapses
+    expires: u64,
+}
+
+/// Serialized form of `ProxyState`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Snapshot {
+    /// Unix time the snapshot was taken
+    saved_at: u64,
+    bans: HashMap<String, u64>,
+    stats: HashMap<String, BackendStats>,
+    sessions: HashMap<String, SessionBinding>,
+    /// Bandwidth counters, restored regardless of the snapshot's age
+    usage: Option<UsageState>,
+}
+
+impl ProxyState {
+    /// Weight of the newest sample in the latency average
+    const LATENCY_WEIGHT: f64 = 0.2;
+
+    pub fn is_banned(&self, id: &str) -> bool {
+        self.bans.contains_key(id)
+    }
+
+    pub fn ban(&self, id: &str) {
+        self.bans.insert(id.to_string(), unix_now());
+    }
+
+    pub fn ban_count(&self) -> usize {
+        self.bans.len()
+    }
+
+    pub fn record_success(&self, id: &str, latency: Duration) {
+        let mut stats = self.stats.entry(id.to_string()).or_default();
+        let sample = latency.as_secs_f64() * 1000.0;
+        stats.latency_ms = if stats.successes == 0 {
+            sample
+        } else {
+            stats.latency_ms + Self::LATENCY_WEIGHT * (sample - stats.latency_ms)
+        };
+        stats.successes += 1;
+        stats.last_used = unix_now();
+    }
+
+    pub fn record_failure(&self, id: &str) {
+        let mut stats = self.stats.entry(id.to_string()).or_default();
+        stats.failures += 1;
+        stats.last_used = unix_now();
+    }
+
+    /// Returns the backend a session is pinned to, dropping the binding if it lapsed
+    pub fn session_backend(&self, session: &str) -> Option<String> {
+        let now = unix_now();
+        let binding = self.sessions.get(session)?;
+        if binding.expires > now {
+            return Some(binding.backend.clone());
+        }
+        drop(binding);
+        self.sessions.remove(session);
+        None
+    }
+
+    pub fn bind_session(&self, session: &str, backend: &str, ttl: Duration) {
+        self.sessions.insert(
+            session.to_string(),
+            SessionBinding {
+                backend: backend.to_string(),
+                expires: unix_now() + ttl.as_secs(),
+            },
+        );
+    }
+
+    /// Captures the current state, pruning lapsed sessions on the way
+    pub fn snapshot(&self) -> Snapshot {
+        let now = unix_now();
+        self.sessions.retain(|_, binding| binding.expires > now);
+        Snapshot {
+            saved_at: now,
+            bans: collect(&self.bans),
+            stats: collect(&self.stats),
+            sessions: collect(&self.sessions),
+            usage: None,
+        }
+    }
+
+    /// Loads a snapshot, skipping it entirely when older than `max_age_secs`
+    /// and dropping bans older than `ban_max_age_secs`
+    pub fn restore(&self, snapshot: Snapshot, config: &StateConfig) {
+        let now = unix_now();
+        let age = now.saturating_sub(snapshot.saved_at);
+        if age > config.max_age_secs {
+            info!(
+                "Saved state is {age}s old (limit {}s); starting fresh",
+                config.max_age_secs
+            );
+            return;
+        }
+
+        let bans: Vec<_> = snapshot
+            .bans
+            .into_iter()
+            .filter(|(_, at)| now.saturating_sub(*at) <= config.ban_max_age_secs)
+            .collect();
+        let sessions: Vec<_> = snapshot
+            .sessions
+            .into_iter()
+            .filter(|(_, binding)| binding.expires > now)
+            .collect();
+        info!(
+            "Restored {} bans, {} backend stats and {} sticky sessions",
+            bans.len(),
+            snapshot.stats.len(),
+            sessions.len()
+        );
+
+        for (id, at) in bans {
+            self.bans.insert(id, at);
+        }
+        for (id, stats) in snapshot.stats {
+            self.stats.insert(id, stats);
+        }
+        for (session, binding) in sessions {
+            self.sessions.insert(session, binding);
+        }
+    }
+}
+
+fn collect<V: Clone>(map: &DashMap<String, V>) -> HashMap<String, V> {
+    map.iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Where state snapshots are kept
+pub trait StateBackend: Send + Sync {
+    /// Returns the last saved snapshot, if any
+    fn load(&self) -> Result<Option<Snapshot>>;
+    fn save(&self, snapshot: &Snapshot) -> Result<()>;
+}
+
+/// Keeps the snapshot in a local JSON file, replaced atomically on every save
+pub struct JsonFileBackend {
+    path: PathBuf,
+}
+
+impl JsonFileBackend {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl StateBackend for JsonFileBackend {
+    fn load(&self) -> Result<Option<Snapshot>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let raw = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read state {}", self.path.display()))?;
+        serde_json::from_str(&raw)
+            .map(Some)
+            .with_context(|| format!("invalid state in {}", self.path.display()))
+    }
+
+    fn save(&self, snapshot: &Snapshot) -> Result<()> {
+        let raw = serde_json::to_string(snapshot).context("failed to encode state")?;
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, raw).with_context(|| format!("failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("failed to replace {}", self.path.display()))
+    }
+}
+
+/// Saves `ProxyState` and bandwidth usage periodically and on shutdown
+pub struct StateSnapshotter {
+    state: Arc<ProxyState>,
+    usage: Arc<UsageStore>,
+    backend: Box<dyn StateBackend>,
+    interval: Duration,
+}
+
+impl StateSnapshotter {
+    /// Opens the configured backend and restores its last snapshot into `state` and `usage`
+    ///
+    /// Returns None when no state file is configured.
+    pub fn restore(
+        state: Arc<ProxyState>,
+        usage: Arc<UsageStore>,
+        config: &StateConfig,
+    ) -> Option<Self> {
+        let backend = JsonFileBackend::new(config.path.clone()?);
+        match backend.load() {
+            Ok(Some(mut snapshot)) => {
+                if let Some(saved) = snapshot.usage.take() {
+                    usage.restore(saved);
+                }
+                state.restore(snapshot, config);
+            }
+            Ok(None) => {}
+            // A corrupt file only costs the learned state; keep serving
+            Err(err) => warn!("Ignoring saved state: {err:#}"),
+        }
+
+        Some(Self {
+            state,
+            usage,
+            backend: Box::new(backend),
+            interval: config.snapshot_interval(),
+        })
+    }
+
+    fn save(&self) {
+        let snapshot = Snapshot {
+            usage: Some(self.usage.snapshot()),
+            ..self.state.snapshot()
+        };
+        if let Err(err) = self.backend.save(&snapshot) {
+            warn!("Failed to save state: {err:#}");
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for StateSnapshotter {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => self.save(),
+                _ = shutdown.changed() => {
+                    self.save();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BudgetConfig, Config};
+    use crate::proxy_list::ListFormat;
+    use crate::upstream::{ProxyMetadata, parse_proxy_list};
+
+    fn acme() -> ProxyMetadata {
+        let (mut proxies, _) = parse_proxy_list("203.0.113.7:8080", ListFormat::Auto, false);
+        let mut metadata = proxies.remove(0);
+        metadata.attrs.provider = Some("acme".to_string());
+        metadata
+    }
+
+    fn usage() -> Arc<UsageStore> {
+        let mut config = Config::default();
+        config.budgets.push(BudgetConfig {
+            provider: "acme".to_string(),
+            daily: Some(100),
+            monthly: None,
+        });
+        Arc::new(UsageStore::new(&config))
+    }
+
+    fn config(dir: &tempfile::TempDir) -> StateConfig {
+        StateConfig {
+            path: Some(dir.path().join("state.json")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn snapshot_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+
+        let state = Arc::new(ProxyState::default());
+        let counted = usage();
+        state.ban("banned");
+        state.record_success("fast", Duration::from_millis(40));
+        state.bind_session("s1", "fast", Duration::from_secs(600));
+        counted.record(&acme(), None, 100);
+        StateSnapshotter::restore(state, counted, &config)
+            .unwrap()
+            .save();
+
+        let state = Arc::new(ProxyState::default());
+        let restored = usage();
+        StateSnapshotter::restore(state.clone(), restored.clone(), &config).unwrap();
+        assert!(state.is_banned("banned"));
+        assert_eq!(state.session_backend("s1").as_deref(), Some("fast"));
+        assert_eq!(state.stats.get("fast").unwrap().successes, 1);
+        assert!(restored.is_exhausted(&acme()));
+    }
+
+    #[test]
+    fn stale_snapshot_only_restores_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let counted = usage();
+        counted.record(&acme(), None, 100);
+        let now = unix_now();
+        let snapshot = Snapshot {
+            saved_at: now - config.max_age_secs - 1,
+            bans: HashMap::from([("banned".to_string(), now)]),
+            usage: Some(counted.snapshot()),
+            ..Default::default()
+        };
+        JsonFileBackend::new(config.path.clone().unwrap())
+            .save(&snapshot)
+            .unwrap();
+
+        let state = Arc::new(ProxyState::default());
+        let restored = usage();
+        StateSnapshotter::restore(state.clone(), restored.clone(), &config).unwrap();
+        assert!(!state.is_banned("banned"));
+        assert!(restored.is_exhausted(&acme()));
+    }
+
+    #[test]
+    fn old_bans_and_lapsed_sessions_are_dropped() {
+        let config = StateConfig::default();
+        let now = unix_now();
+        let binding = |expires| SessionBinding {
+            backend: "b".to_string(),
+            expires,
+        };
+        let snapshot = Snapshot {
+            saved_at: now,
+            bans: HashMap::from([
+                ("old".to_string(), now - config.ban_max_age_secs - 1),
+                ("recent".to_string(), now - 60),
+            ]),
+            sessions: HashMap::from([
+                ("lapsed".to_string(), binding(now - 1)),
+                ("live".to_string(), binding(now + 60)),
+            ]),
+            ..Default::default()
+        };
+
+        let state = ProxyState::default();
+        state.restore(snapshot, &config);
+        assert!(!state.is_banned("old"));
+        assert!(state.is_banned("recent"));
+        assert_eq!(state.session_backend("lapsed"), None);
+        assert_eq!(state.session_backend("live").as_deref(), Some("b"));
+    }
+}
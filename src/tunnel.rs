@@ -0,0 +1,430 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use pingora_core::server::ShutdownWatch;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, watch};
+use tokio::time::{Instant, sleep_until, timeout};
+use tracing::info;
+
+/// Tracks active tunnels so shutdown can drain them within a grace period
+pub struct TunnelTracker {
+    active: AtomicUsize,
+    draining: AtomicBool,
+    drained: Notify,
+    force_close: watch::Sender<bool>,
+    grace_period: Duration,
+}
+
+impl TunnelTracker {
+    /// Creates a new tracker wrapped in Arc for shared access
+    pub fn new(grace_period: Duration) -> Arc<Self> {
+        let (force_close, _) = watch::channel(false);
+        Arc::new(Self {
+            active: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            drained: Notify::new(),
+            force_close,
+            grace_period,
+        })
+    }
+
+    /// Registers a tunnel; it stays active until the guard is dropped
+    pub fn register(self: &Arc<Self>) -> TunnelGuard {
+        self.active.fetch_add(1, Ordering::AcqRel);
+        TunnelGuard {
+            tracker: Arc::clone(self),
+        }
+    }
+
+    /// Returns the number of active tunnels
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Returns true once shutdown has started draining tunnels
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Starts draining (once); tunnels still active after the grace period are force-closed
+    pub fn begin_drain(self: &Arc<Self>) {
+        if self.draining.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let tracker = Arc::clone(self);
+        tokio::spawn(async move { tracker.drain().await });
+    }
+
+    async fn drain(&self) {
+        info!(
+            "Shutdown started; draining {} active tunnels (grace period {:?})",
+            self.active(),
+            self.grace_period
+        );
+
+        let all_closed = async {
+            loop {
+                // Register interest before checking so a concurrent drop is not missed
+                let notified = self.drained.notified();
+                if self.active() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+
+        if timeout(self.grace_period, all_closed).await.is_ok() {
+            info!("All tunnels drained before grace period elapsed");
+            return;
+        }
+
+        let cut = self.active();
+        self.force_close.send_replace(true);
+        info!("Grace period elapsed; force-closing {cut} tunnels");
+    }
+}
+
+/// Registration of a single active tunnel
+pub struct TunnelGuard {
+    tracker: Arc<TunnelTracker>,
+}
+
+impl TunnelGuard {
+    /// Resolves when this tunnel must be torn down: shutdown started and the grace period elapsed
+    pub async fn force_closed(&self, shutdown: &ShutdownWatch) {
+        let mut shutdown = shutdown.clone();
+        if shutdown.wait_for(|stopping| *stopping).await.is_err() {
+            // Shutdown sender is gone; the server is not going to signal us
+            return std::future::pending().await;
+        }
+        self.tracker.begin_drain();
+
+        let mut force_close = self.tracker.force_close.subscribe();
+        let _ = force_close.wait_for(|forced| *forced).await;
+    }
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        if self.tracker.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.tracker.drained.notify_waiters();
+        }
+    }
+}
+
+/// Timeouts applied to an established tunnel
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelPolicy {
+    /// Close when no bytes flow in either direction for this long
+    pub idle_timeout: Option<Duration>,
+    /// Close when the tunnel has been open this long, regardless of activity
+    pub max_lifetime: Option<Duration>,
+    /// Close when one direction has finished and the other has not within this long
+    pub half_close_timeout: Option<Duration>,
+    /// Close when nothing arrived from upstream this long after the client's first byte
+    ///
+    /// A client that has not spoken yet is only subject to the idle timeout.
+    pub first_byte_timeout: Option<Duration>,
+    /// Stop reading from the client after this many bytes, leaving the rest unforwarded
+    pub upload_limit: Option<u64>,
+}
+
+/// Why a tunnel was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Both sides finished sending
+    Completed,
+    /// Read or write failed on either side
+    Error,
+    /// No bytes in either direction within the idle timeout
+    Idle,
+    /// Tunnel exceeded its maximum lifetime
+    MaxLifetime,
+    /// One side stayed open too long after the other finished
+    HalfClose,
+    /// Upstream sent nothing within the first byte timeout
+    FirstByte,
+    /// Closed by shutdown after the grace period
+    Shutdown,
+    /// The progress callback asked to stop, e.g. a bandwidth budget ran out
+    Budget,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Error => "error",
+            Self::Idle => "idle_timeout",
+            Self::MaxLifetime => "max_lifetime",
+            Self::HalfClose => "half_close_timeout",
+            Self::FirstByte => "first_byte_timeout",
+            Self::Shutdown => "shutdown",
+            Self::Budget => "budget_exhausted",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Summary of a finished tunnel
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelReport {
+    pub reason: CloseReason,
+    /// Bytes sent from client to upstream
+    pub bytes_up: u64,
+    /// Bytes sent from upstream to client
+    pub bytes_down: u64,
+    pub duration: Duration,
+}
+
+/// How often a tunnel reports transferred bytes to its progress callback
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Copies data in both directions until both sides finish, a policy timeout fires, or `cancel` resolves
+///
+/// Every [`PROGRESS_INTERVAL`] and once at close, `on_progress` receives the bytes moved
+/// since its previous call; returning false closes the tunnel with [`CloseReason::Budget`].
+pub async fn copy_bidirectional_with_policy<A, B>(
+    client: &mut A,
+    upstream: &mut B,
+    policy: TunnelPolicy,
+    cancel: impl Future<Output = ()>,
+    mut on_progress: impl FnMut(u64) -> bool,
+) -> TunnelReport
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let started = Instant::now();
+    let last_activity = AtomicU64::new(0);
+    let bytes_up = AtomicU64::new(0);
+    let bytes_down = AtomicU64::new(0);
+    let first_sent = Notify::new();
+
+    let (mut client_read, mut client_write) = io::split(client);
+    let (mut upstream_read, mut upstream_write) = io::split(upstream);
+
+    let up = pump(
+        &mut client_read,
+        &mut upstream_write,
+        policy.upload_limit,
+        started,
+        &last_activity,
+        &bytes_up,
+        Some(&first_sent),
+    );
+    let down = pump(
+        &mut upstream_read,
+        &mut client_write,
+        None,
+        started,
+        &last_activity,
+        &bytes_down,
+        None,
+    );
+    tokio::pin!(up, down, cancel);
+
+    let mut up_done = false;
+    let mut down_done = false;
+    let mut half_closed_at: Option<Instant> = None;
+    let mut first_sent_at: Option<Instant> = None;
+    let mut reported = 0u64;
+    let mut take_progress = || {
+        let total = bytes_up.load(Ordering::Relaxed) + bytes_down.load(Ordering::Relaxed);
+        total - std::mem::replace(&mut reported, total)
+    };
+    let mut progress_deadline = started + PROGRESS_INTERVAL;
+
+    let reason = loop {
+        if up_done && down_done {
+            break CloseReason::Completed;
+        }
+
+        let idle_deadline = policy.idle_timeout.map(|idle| {
+            started + Duration::from_millis(last_activity.load(Ordering::Relaxed)) + idle
+        });
+        let lifetime_deadline = policy.max_lifetime.map(|max| started + max);
+        let half_close_deadline = half_closed_at.zip(policy.half_close_timeout).map(|(at, t)| at + t);
+        let first_byte_deadline = first_sent_at
+            .zip(policy.first_byte_timeout)
+            .filter(|_| bytes_down.load(Ordering::Relaxed) == 0)
+            .map(|(at, t)| at + t);
+        let next_deadline = [
+            idle_deadline,
+            lifetime_deadline,
+            half_close_deadline,
+            first_byte_deadline,
+            Some(progress_deadline),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+
+        tokio::select! {
+            res = &mut up, if !up_done => {
+                let Ok(eof) = res else {
+                    break CloseReason::Error;
+                };
+                up_done = true;
+                // A client cut off at the upload limit is still waiting for the response
+                if eof {
+                    half_closed_at.get_or_insert_with(Instant::now);
+                }
+            }
+            res = &mut down, if !down_done => {
+                if res.is_err() {
+                    break CloseReason::Error;
+                }
+                down_done = true;
+                half_closed_at.get_or_insert_with(Instant::now);
+            }
+            _ = first_sent.notified(), if first_sent_at.is_none() => {
+                first_sent_at = Some(Instant::now());
+            }
+            _ = &mut cancel => break CloseReason::Shutdown,
+            _ = sleep_until_opt(next_deadline) => {
+                let now = Instant::now();
+                if lifetime_deadline.is_some_and(|d| now >= d) {
+                    break CloseReason::MaxLifetime;
+                }
+                if half_close_deadline.is_some_and(|d| now >= d) {
+                    break CloseReason::HalfClose;
+                }
+                if first_byte_deadline.is_some_and(|d| now >= d)
+                    && bytes_down.load(Ordering::Relaxed) == 0
+                {
+                    break CloseReason::FirstByte;
+                }
+                // Activity may have moved the idle deadline while we slept
+                let idle_deadline = policy.idle_timeout.map(|idle| {
+                    started + Duration::from_millis(last_activity.load(Ordering::Relaxed)) + idle
+                });
+                if idle_deadline.is_some_and(|d| now >= d) {
+                    break CloseReason::Idle;
+                }
+                if now >= progress_deadline {
+                    progress_deadline = now + PROGRESS_INTERVAL;
+                    if !on_progress(take_progress()) {
+                        break CloseReason::Budget;
+                    }
+                }
+            }
+        }
+    };
+    on_progress(take_progress());
+
+    TunnelReport {
+        reason,
+        bytes_up: bytes_up.load(Ordering::Relaxed),
+        bytes_down: bytes_down.load(Ordering::Relaxed),
+        duration: started.elapsed(),
+    }
+}
+
+/// Copies one direction, half-closing the writer once the reader reaches EOF
+///
+/// Returns true at EOF and false once `limit` bytes were copied, leaving the writer open.
+/// `first_sent` is notified once the first bytes went through.
+async fn pump<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    mut limit: Option<u64>,
+    started: Instant,
+    last_activity: &AtomicU64,
+    bytes: &AtomicU64,
+    mut first_sent: Option<&Notify>,
+) -> io::Result<bool>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let wanted = match limit {
+            Some(0) => return Ok(false),
+            Some(left) => buf.len().min(left as usize),
+            None => buf.len(),
+        };
+        let n = reader.read(&mut buf[..wanted]).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(true);
+        }
+        if let Some(left) = &mut limit {
+            *left -= n as u64;
+        }
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(first_sent) = first_sent.take() {
+            first_sent.notify_one();
+        }
+        last_activity.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+async fn sleep_until_opt(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::sleep;
+
+    use super::*;
+
+    fn first_byte_policy(first_byte: Duration) -> TunnelPolicy {
+        TunnelPolicy {
+            idle_timeout: None,
+            max_lifetime: None,
+            half_close_timeout: None,
+            first_byte_timeout: Some(first_byte),
+            upload_limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_client_is_not_held_to_the_first_byte_timeout() {
+        let (mut client, _client_side) = io::duplex(64);
+        let (mut upstream, _upstream_side) = io::duplex(64);
+        let report = copy_bidirectional_with_policy(
+            &mut client,
+            &mut upstream,
+            first_byte_policy(Duration::from_millis(50)),
+            sleep(Duration::from_millis(300)),
+            |_| true,
+        )
+        .await;
+        assert_eq!(report.reason, CloseReason::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn silent_target_is_closed_after_the_clients_first_byte() {
+        let (mut client, mut client_side) = io::duplex(64);
+        let (mut upstream, _upstream_side) = io::duplex(64);
+        client_side.write_all(b"hello").await.unwrap();
+        let report = copy_bidirectional_with_policy(
+            &mut client,
+            &mut upstream,
+            first_byte_policy(Duration::from_millis(50)),
+            sleep(Duration::from_secs(5)),
+            |_| true,
+        )
+        .await;
+        assert_eq!(report.reason, CloseReason::FirstByte);
+        assert_eq!(report.bytes_up, 5);
+    }
+}
@@ -1,119 +1,345 @@
-use std::{fs, net::ToSocketAddrs, path::Path};
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD as BASE64_ENGINE;
+use pingora_core::tls::x509::X509;
 use pingora_load_balancing::Backend;
-use url::Url;
+use tracing::warn;
+
+use crate::config::DEFAULT_POOL;
+use crate::exit_ip::ExitIp;
+use crate::geo::GeoLocation;
+use crate::rate_limit::RateLimiter;
+use crate::proxy_list::{LineError, ListFormat, ProxyAttributes, ProxyEntry, parse_entries};
+use crate::username_template::{SessionHints, UsernameTemplate};
+
+/// Scheme for list entries that do not name one
+pub const DEFAULT_SCHEME: &str = "http";
 
 /// Proxy backend metadata extracted from URL
 #[derive(Debug, Clone)]
 pub struct ProxyMetadata {
+    /// Stable identity used for bans and deduplication; shared by all resolved addresses
+    pub id: String,
     pub scheme: String,
     pub host: String,
     pub port: u16,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Provider username layout expanded per request from session hints
+    pub username_template: Option<UsernameTemplate>,
+    pub tls: UpstreamTls,
+    pub attrs: ProxyAttributes,
+    /// Address the proxy was last seen exiting from, when exit IP probing is enabled
+    pub exit_ip: ExitIp,
+    /// Where the geo database places the proxy's own address
+    pub gateway_location: Option<GeoLocation>,
+    /// Request limits, shared by all addresses of the proxy
+    pub limiter: Option<RateLimiter>,
+    /// Pool the proxy belongs to
+    pub pool: String,
     pub original: String,
 }
 
 impl ProxyMetadata {
     /// Returns Basic auth header if username and password are present
-    pub fn basic_auth_header(&self) -> Option<String> {
+    ///
+    /// With a username template the username is expanded from `hints` first, so each
+    /// request can ask the provider for a different country or session.
+    pub fn basic_auth_header(&self, hints: &SessionHints) -> Option<String> {
         match (&self.username, &self.password) {
             (Some(user), Some(pass)) => {
+                let user = match &self.username_template {
+                    Some(template) => template.expand(user, hints),
+                    None => user.clone(),
+                };
                 let encoded = BASE64_ENGINE.encode(format!("{}:{}", user, pass));
                 Some(format!("Basic {}", encoded))
             }
             _ => None,
         }
     }
-}
 
-/// Loads proxy backends from config file (one URL per line)
-pub fn load_backends_from_file(path: impl AsRef<Path>) -> Result<Vec<Backend>> {
-    let raw = fs::read_to_string(path.as_ref())
-        .with_context(|| format!("failed to read proxy list from {}", path.as_ref().display()))?;
+    /// Derives the backend ID from scheme, host, port and username
+    ///
+    /// Entries sharing a gateway `host:port` with different usernames (country or
+    /// session variants) stay distinct.
+    fn derive_id(scheme: &str, host: &str, port: u16, username: Option<&str>) -> String {
+        format!(
+            "{}://{}@{}:{}",
+            scheme.to_ascii_lowercase(),
+            username.unwrap_or_default(),
+            host.to_ascii_lowercase(),
+            port
+        )
+    }
+
+    /// Returns the proxy's country: from its list entry, else located from the probed
+    /// exit IP, else from its own address
+    pub fn country(&self) -> Option<String> {
+        self.locate(self.attrs.country.as_deref(), |location| location.country)
+    }
 
-    let mut result = Vec::new();
+    /// Returns the proxy's city, from the same sources as `country`
+    pub fn city(&self) -> Option<String> {
+        self.locate(self.attrs.city.as_deref(), |location| location.city)
+    }
 
-    for (idx, line) in raw.lines().enumerate() {
-        let trimmed = line.trim();
+    fn locate(
+        &self,
+        listed: Option<&str>,
+        field: impl Fn(GeoLocation) -> Option<String>,
+    ) -> Option<String> {
+        if let Some(listed) = listed {
+            return Some(listed.to_string());
+        }
+        if self.exit_ip.get().is_some() {
+            self.exit_ip.location().and_then(field)
+        } else {
+            self.gateway_location.clone().and_then(field)
+        }
+    }
 
-        // Skip empty lines and comments
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            continue;
+    /// Returns the proxy URL without its password, for display
+    pub fn label(&self) -> String {
+        match &self.username {
+            Some(user) => format!("{}://{}@{}:{}", self.scheme, user, self.host, self.port),
+            None => format!("{}://{}:{}", self.scheme, self.host, self.port),
         }
+    }
+}
 
-        let url = Url::parse(trimmed)
-            .with_context(|| format!("invalid proxy url on line {}: {}", idx + 1, trimmed))?;
-
-        let host = url
-            .host_str()
-            .with_context(|| format!("missing host for proxy url on line {}", idx + 1))?
-            .to_string();
-
-        let port = url
-            .port_or_known_default()
-            .ok_or_else(|| anyhow::anyhow!("missing port for proxy url on line {}", idx + 1))?;
-
-        let socket_addr_str = format!("{}:{}", host, port);
-
-        // Create backend, resolving DNS if needed
-        let mut backend = match Backend::new(&socket_addr_str) {
-            Ok(b) => b,
-            Err(_) => {
-                match socket_addr_str.to_socket_addrs() {
-                    Ok(mut addrs) => {
-                        if let Some(addr) = addrs.next() {
-                            Backend::new(&addr.to_string()).with_context(|| {
-                                format!(
-                                    "failed to create backend for {} (resolved to {})",
-                                    socket_addr_str, addr
-                                )
-                            })?
-                        } else {
-                            anyhow::bail!(
-                                "could not resolve hostname {} on line {}",
-                                host,
-                                idx + 1
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        anyhow::bail!(
-                            "could not resolve hostname {} on line {} - {}",
-                            host,
-                            idx + 1,
-                            e
-                        );
+/// TLS settings for `https://` upstream proxies, set via URL query parameters
+///
+/// `ca=/path/bundle.pem` trusts a custom CA bundle, `pin=<sha256 hex>` (repeatable)
+/// pins the proxy certificate and `insecure=true` disables chain and hostname
+/// verification. Pins are checked on top of verification, and on their own with
+/// `insecure=true` for self-signed proxies.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTls {
+    pub ca: Option<Arc<Box<[X509]>>>,
+    pub pins: Vec<Vec<u8>>,
+    pub insecure: bool,
+}
+
+impl UpstreamTls {
+    /// Parses TLS options from proxy URL query parameters
+    fn from_options(options: &[(String, String)]) -> Result<Self> {
+        let mut tls = Self::default();
+
+        for (key, value) in options {
+            match key.as_str() {
+                "ca" => {
+                    let pem = fs::read(value)
+                        .with_context(|| format!("failed to read CA bundle {value}"))?;
+                    let certs = X509::stack_from_pem(&pem)
+                        .with_context(|| format!("invalid CA bundle {value}"))?;
+                    if certs.is_empty() {
+                        anyhow::bail!("CA bundle {value} contains no certificates");
                     }
+                    tls.ca = Some(Arc::new(certs.into_boxed_slice()));
+                }
+                "pin" => tls.pins.push(parse_fingerprint(value)?),
+                "insecure" => {
+                    tls.insecure = value
+                        .parse()
+                        .with_context(|| format!("invalid insecure value {value}"))?;
                 }
+                other => warn!("Ignoring unknown proxy url parameter {other}"),
             }
-        };
+        }
 
-        let username = if url.username().is_empty() {
-            None
-        } else {
-            Some(url.username().to_string())
+        Ok(tls)
+    }
+
+    /// Returns true if the certificate chain and hostname should be verified
+    pub fn verify(&self) -> bool {
+        !self.insecure
+    }
+
+    /// Returns true if the SHA-256 certificate digest matches a pin (or none are configured)
+    pub fn matches_pin(&self, cert_digest: &[u8]) -> bool {
+        self.pins.is_empty() || self.pins.iter().any(|pin| pin == cert_digest)
+    }
+}
+
+/// Parses a SHA-256 fingerprint given as hex, optionally colon-separated and `sha256:` prefixed
+fn parse_fingerprint(raw: &str) -> Result<Vec<u8>> {
+    let hex: String = raw
+        .trim_start_matches("sha256:")
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("certificate pin {raw} is not a hex SHA-256 fingerprint");
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).context("invalid hex in certificate pin")
+        })
+        .collect()
+}
+
+/// Parses every proxy list entry, collecting failures instead of stopping at the first
+pub fn parse_proxy_list(
+    raw: &str,
+    format: ListFormat,
+    decode_credentials: bool,
+) -> (Vec<ProxyMetadata>, Vec<LineError>) {
+    let (entries, mut errors) = parse_entries(raw, format, decode_credentials);
+    let (proxies, build_errors) = build_proxies(entries);
+    errors.extend(build_errors);
+    errors.sort_by_key(|error| error.line);
+    (proxies, errors)
+}
+
+/// Builds proxy metadata from parsed entries; hostnames are resolved later by the DNS cache
+pub fn build_proxies(entries: Vec<ProxyEntry>) -> (Vec<ProxyMetadata>, Vec<LineError>) {
+    let mut proxies = Vec::with_capacity(entries.len());
+    let mut errors = Vec::new();
+
+    for mut entry in entries {
+        if entry.scheme.is_empty() {
+            entry.scheme = DEFAULT_SCHEME.to_string();
+        }
+
+        let (line, text) = (entry.line, entry.original.clone());
+        match build_proxy(entry) {
+            Ok(proxy) => proxies.push(proxy),
+            Err(error) => errors.push(LineError { line, text, error }),
+        }
+    }
+
+    (proxies, errors)
+}
+
+/// Builds proxy metadata from a parsed list entry
+fn build_proxy(entry: ProxyEntry) -> Result<ProxyMetadata> {
+    let id = entry.id.unwrap_or_else(|| {
+        ProxyMetadata::derive_id(&entry.scheme, &entry.host, entry.port, entry.username.as_deref())
+    });
+
+    Ok(ProxyMetadata {
+        id,
+        tls: UpstreamTls::from_options(&entry.options).context("invalid TLS options")?,
+        scheme: entry.scheme,
+        host: entry.host,
+        port: entry.port,
+        username: entry.username,
+        password: entry.password,
+        username_template: None,
+        attrs: entry.attrs,
+        exit_ip: ExitIp::default(),
+        gateway_location: None,
+        limiter: None,
+        pool: DEFAULT_POOL.to_string(),
+        original: entry.original,
+    })
+}
+
+/// Returns the backend ID, falling back to the socket address for backends without metadata
+pub fn backend_id(backend: &Backend) -> String {
+    backend
+        .ext
+        .get::<ProxyMetadata>()
+        .map(|m| m.id.clone())
+        .unwrap_or_else(|| backend.addr.to_string())
+}
+
+/// Creates a backend for one resolved address of a proxy, carrying its metadata
+pub fn backend_for(metadata: &ProxyMetadata, ip: IpAddr) -> Result<Backend> {
+    let addr = SocketAddr::new(ip, metadata.port);
+    let mut backend = Backend::new(&addr.to_string())
+        .with_context(|| format!("failed to create backend for {} ({addr})", metadata.host))?;
+    backend.ext.insert(metadata.clone());
+    Ok(backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(pairs: &[(&str, &str)]) -> UpstreamTls {
+        let pairs: Vec<(String, String)> = pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        UpstreamTls::from_options(&pairs).unwrap()
+    }
+
+    #[test]
+    fn parses_fingerprint_forms() {
+        let plain = "0123456789abcdef".repeat(4);
+        let expected = parse_fingerprint(&plain).unwrap();
+        assert_eq!(expected.len(), 32);
+        assert_eq!(expected[..2], [0x01, 0x23]);
+
+        let colons = expected
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), expected);
+        let prefixed = format!("sha256:{plain}");
+        assert_eq!(parse_fingerprint(&prefixed).unwrap(), expected);
+
+        assert!(parse_fingerprint("abcd").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn verifies_unless_insecure() {
+        assert!(UpstreamTls::default().verify());
+        assert!(!options(&[("insecure", "true")]).verify());
+    }
+
+    #[test]
+    fn matches_any_pin_or_none() {
+        assert!(UpstreamTls::default().matches_pin(&[1, 2, 3]));
+        let tls = UpstreamTls {
+            pins: vec![vec![1; 32], vec![2; 32]],
+            ..Default::default()
         };
+        assert!(tls.matches_pin(&[2; 32]));
+        assert!(!tls.matches_pin(&[3; 32]));
+    }
 
-        let metadata = ProxyMetadata {
-            scheme: url.scheme().to_string(),
-            host: host.clone(),
-            port,
-            username,
-            password: url.password().map(|s| s.to_string()),
-            original: trimmed.to_string(),
+    #[test]
+    fn locates_by_exit_ip_before_gateway() {
+        let located = |country: &str| GeoLocation {
+            country: Some(country.to_string()),
+            city: None,
         };
+        let (mut proxies, _) = parse_proxy_list("203.0.113.7:8080", ListFormat::Auto, false);
+        let mut proxy = proxies.remove(0);
+        assert_eq!(proxy.country(), None);
 
-        backend.ext.insert(metadata);
-        result.push(backend);
-    }
+        proxy.gateway_location = Some(located("nl"));
+        assert_eq!(proxy.country().as_deref(), Some("nl"));
+
+        // Once probed, the exit IP decides, even when the database has no record for it
+        proxy
+            .exit_ip
+            .record("198.51.100.1".parse().unwrap(), Some(located("us")));
+        assert_eq!(proxy.country().as_deref(), Some("us"));
+        proxy.exit_ip.record("198.51.100.2".parse().unwrap(), None);
+        assert_eq!(proxy.country(), None);
 
-    if result.is_empty() {
-        anyhow::bail!("no proxies loaded from {}", path.as_ref().display());
+        proxy.attrs.country = Some("de".to_string());
+        assert_eq!(proxy.country().as_deref(), Some("de"));
     }
 
-    Ok(result)
+    #[test]
+    fn ignores_unknown_parameters() {
+        let tls = options(&[("region", "eu"), ("insecure", "true")]);
+        assert!(tls.insecure);
+    }
 }
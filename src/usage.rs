@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use dashmap::DashSet;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::{BudgetConfig, Config};
+use crate::upstream::ProxyMetadata;
+
+/// Bytes transferred per backend, provider and client user, with daily and monthly budgets
+///
+/// Open tunnels report their bytes every few seconds and close once their provider runs
+/// out of budget, so a budget is overshot by at most a few seconds of traffic. The
+/// counters are saved with the rest of the state snapshot (see `state::StateSnapshotter`).
+pub struct UsageStore {
+    budgets: Vec<BudgetConfig>,
+    state: Mutex<UsageState>,
+    /// Providers over budget, read on every selection
+    exhausted: DashSet<String>,
+}
+
+/// Counters of the current UTC day and month, as kept in state snapshots
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageState {
+    /// UTC day the daily counters belong to, `YYYY-MM-DD`
+    day: String,
+    /// UTC month the monthly counters belong to, `YYYY-MM`
+    month: String,
+    daily: Counters,
+    monthly: Counters,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Counters {
+    backends: HashMap<String, u64>,
+    providers: HashMap<String, u64>,
+    users: HashMap<String, u64>,
+}
+
+impl UsageState {
+    /// Moves to the current day and month, resetting counters of past ones; returns true on change
+    fn roll_over(&mut self) -> bool {
+        let (day, month) = today();
+        if self.day == day && self.month == month {
+            return false;
+        }
+
+        if self.day != day {
+            self.day = day;
+            self.daily = Counters::default();
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly = Counters::default();
+        }
+        true
+    }
+}
+
+impl Counters {
+    fn add(&mut self, metadata: &ProxyMetadata, user: Option<&str>, bytes: u64) {
+        *self.backends.entry(metadata.id.clone()).or_default() += bytes;
+        if let Some(provider) = &metadata.attrs.provider {
+            *self.providers.entry(provider.clone()).or_default() += bytes;
+        }
+        if let Some(user) = user {
+            *self.users.entry(user.to_string()).or_default() += bytes;
+        }
+    }
+}
+
+impl UsageStore {
+    /// How often day and month boundaries are checked while no traffic is recorded
+    const ROLL_OVER_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new(config: &Config) -> Self {
+        let store = Self {
+            budgets: config.budgets.clone(),
+            state: Mutex::new(UsageState::default()),
+            exhausted: DashSet::new(),
+        };
+        store.roll_over();
+        store
+    }
+
+    /// Returns a copy of the counters for a state snapshot
+    pub fn snapshot(&self) -> UsageState {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replaces the counters with saved ones; counters of a past day or month are reset
+    pub fn restore(&self, mut saved: UsageState) {
+        saved.roll_over();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = saved;
+        self.update_exhausted(&state);
+    }
+
+    /// Adds bytes moved by a tunnel since its last report
+    pub fn record(&self, metadata: &ProxyMetadata, user: Option<&str>, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        self.roll_over();
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.daily.add(metadata, user, bytes);
+        state.monthly.add(metadata, user, bytes);
+
+        if let Some(provider) = &metadata.attrs.provider
+            && !self.exhausted.contains(provider)
+            && self.over_budget(&state, provider)
+        {
+            warn!("Bandwidth budget of provider {provider} exhausted; its proxies are disabled");
+            self.exhausted.insert(provider.clone());
+        }
+    }
+
+    /// Returns true if the backend's provider has used up a budget
+    pub fn is_exhausted(&self, metadata: &ProxyMetadata) -> bool {
+        !self.exhausted.is_empty()
+            && metadata
+                .attrs
+                .provider
+                .as_ref()
+                .is_some_and(|provider| self.exhausted.contains(provider))
+    }
+
+    fn over_budget(&self, state: &UsageState, provider: &str) -> bool {
+        let used = |counters: &Counters| counters.providers.get(provider).copied().unwrap_or(0);
+        self.budgets
+            .iter()
+            .filter(|budget| budget.provider == provider)
+            .any(|budget| {
+                budget.daily.is_some_and(|limit| used(&state.daily) >= limit)
+                    || budget.monthly.is_some_and(|limit| used(&state.monthly) >= limit)
+            })
+    }
+
+    /// Resets counters whose day or month has passed and re-evaluates budgets
+    fn roll_over(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.roll_over() {
+            self.update_exhausted(&state);
+        }
+    }
+
+    fn update_exhausted(&self, state: &UsageState) {
+        self.exhausted.clear();
+        for budget in &self.budgets {
+            if self.over_budget(state, &budget.provider) {
+                self.exhausted.insert(budget.provider.clone());
+            }
+        }
+        if !self.exhausted.is_empty() {
+            let providers: Vec<String> = self.exhausted.iter().map(|p| p.clone()).collect();
+            info!("Providers over bandwidth budget: {}", providers.join(", "));
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UsageStore {
+    /// Resets counters at day and month boundaries, re-enabling providers over budget
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Self::ROLL_OVER_INTERVAL) => self.roll_over(),
+                _ = shutdown.changed() => return,
+            }
+        }
+    }
+}
+
+/// Returns the current UTC day (`YYYY-MM-DD`) and month (`YYYY-MM`)
+fn today() -> (String, String) {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or(0) as i64;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        format!("{year:04}-{month:02}-{day:02}"),
+        format!("{year:04}-{month:02}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_list::ListFormat;
+    use crate::upstream::parse_proxy_list;
+
+    fn backend(provider: &str) -> ProxyMetadata {
+        let (mut proxies, _) = parse_proxy_list("203.0.113.7:8080", ListFormat::Auto, false);
+        let mut metadata = proxies.remove(0);
+        metadata.attrs.provider = Some(provider.to_string());
+        metadata
+    }
+
+    fn store(daily: u64) -> UsageStore {
+        let mut config = Config::default();
+        config.budgets.push(BudgetConfig {
+            provider: "acme".to_string(),
+            daily: Some(daily),
+            monthly: None,
+        });
+        UsageStore::new(&config)
+    }
+
+    #[test]
+    fn counts_bytes_per_backend_provider_and_user() {
+        let usage = store(1000);
+        let acme = backend("acme");
+        usage.record(&acme, Some("alice"), 300);
+        usage.record(&acme, None, 200);
+
+        let state = usage.snapshot();
+        for counters in [&state.daily, &state.monthly] {
+            assert_eq!(counters.backends[&acme.id], 500);
+            assert_eq!(counters.providers["acme"], 500);
+            assert_eq!(counters.users["alice"], 300);
+        }
+    }
+
+    #[test]
+    fn provider_is_exhausted_once_its_budget_is_reached() {
+        let usage = store(1000);
+        let acme = backend("acme");
+        let other = backend("other");
+
+        usage.record(&acme, None, 999);
+        assert!(!usage.is_exhausted(&acme));
+        usage.record(&acme, None, 1);
+        assert!(usage.is_exhausted(&acme));
+
+        usage.record(&other, None, 5000);
+        assert!(!usage.is_exhausted(&other));
+    }
+
+    #[test]
+    fn restored_counters_of_a_past_day_are_reset() {
+        let exhausted = store(1000);
+        let acme = backend("acme");
+        exhausted.record(&acme, None, 1000);
+        let saved = exhausted.snapshot();
+
+        let usage = store(1000);
+        usage.restore(saved.clone());
+        assert!(usage.is_exhausted(&acme));
+
+        let yesterday = UsageState {
+            day: "2000-01-01".to_string(),
+            ..saved
+        };
+        let usage = store(1000);
+        usage.restore(yesterday);
+        assert!(!usage.is_exhausted(&acme));
+        assert!(usage.snapshot().daily.providers.is_empty());
+    }
+}
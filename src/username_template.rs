@@ -0,0 +1,480 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::config::{DEFAULT_POOL, TimeoutOverrides};
+use crate::upstream::ProxyMetadata;
+
+/// Provider username layout expanded per request, e.g. `customer-{username}[-cc-{country}][-sessid-{session}]`
+///
+/// Placeholders are `{username}` (the configured username), `{country}`, `{city}`,
+/// `{session}` and `{lifetime}`. A `[...]` group is dropped when any placeholder in it
+/// has no value, so optional parameters disappear cleanly.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UsernameTemplate {
+    source: Arc<str>,
+    parts: Arc<[Part]>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Literal(String),
+    Field(Field),
+    Group(Vec<Part>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Username,
+    Country,
+    City,
+    Session,
+    Lifetime,
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "username" => Self::Username,
+            "country" => Self::Country,
+            "city" => Self::City,
+            "session" => Self::Session,
+            "lifetime" => Self::Lifetime,
+            other => bail!("unknown placeholder {{{other}}}"),
+        })
+    }
+}
+
+/// Per-request values for username templates and geo selection, taken from client
+/// request headers or tokens in the client's proxy username
+#[derive(Debug, Clone, Default)]
+pub struct SessionHints {
+    pub country: Option<String>,
+    pub city: Option<String>,
+    /// Sticky session ID; a random one is generated when missing
+    pub session: Option<String>,
+    /// Session lifetime in minutes
+    pub lifetime: Option<u32>,
+    /// Pool to select from; the default pool when unset
+    pub pool: Option<String>,
+    /// Upstream timeouts requested by the client
+    pub timeouts: TimeoutOverrides,
+}
+
+impl SessionHints {
+    pub const COUNTRY_HEADER: &'static str = "x-proxywar-country";
+    pub const CITY_HEADER: &'static str = "x-proxywar-city";
+    pub const SESSION_HEADER: &'static str = "x-proxywar-session";
+    pub const LIFETIME_HEADER: &'static str = "x-proxywar-session-lifetime";
+    pub const POOL_HEADER: &'static str = "x-proxywar-pool";
+    pub const CONNECT_TIMEOUT_HEADER: &'static str = "x-proxywar-connect-timeout";
+    pub const HANDSHAKE_TIMEOUT_HEADER: &'static str = "x-proxywar-handshake-timeout";
+    pub const CONNECT_REPLY_TIMEOUT_HEADER: &'static str = "x-proxywar-connect-reply-timeout";
+    pub const FIRST_BYTE_TIMEOUT_HEADER: &'static str = "x-proxywar-first-byte-timeout";
+
+    /// Returns true if `name` is one of the hint headers, which are not forwarded upstream
+    pub fn is_hint_header(name: &str) -> bool {
+        [
+            Self::COUNTRY_HEADER,
+            Self::CITY_HEADER,
+            Self::SESSION_HEADER,
+            Self::LIFETIME_HEADER,
+            Self::POOL_HEADER,
+            Self::CONNECT_TIMEOUT_HEADER,
+            Self::HANDSHAKE_TIMEOUT_HEADER,
+            Self::CONNECT_REPLY_TIMEOUT_HEADER,
+            Self::FIRST_BYTE_TIMEOUT_HEADER,
+        ]
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header))
+    }
+
+    /// Records one hint header; values must be plain tokens so they cannot inject parameters
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        if value.is_empty()
+            || !value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            bail!("invalid {name} value {value:?}: expected letters, digits or '_'");
+        }
+
+        if name.eq_ignore_ascii_case(Self::COUNTRY_HEADER) {
+            self.country = Some(value.to_ascii_lowercase());
+        } else if name.eq_ignore_ascii_case(Self::CITY_HEADER) {
+            self.city = Some(value.to_ascii_lowercase());
+        } else if name.eq_ignore_ascii_case(Self::SESSION_HEADER) {
+            self.session = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case(Self::LIFETIME_HEADER) {
+            let minutes = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid {name} value {value:?}: expected minutes"))?;
+            self.lifetime = Some(minutes);
+        } else if name.eq_ignore_ascii_case(Self::POOL_HEADER) {
+            self.pool = Some(value.to_string());
+        } else if let Some(timeout) = self.timeout_mut(name) {
+            let ms = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid {name} value {value:?}: expected milliseconds"))?;
+            *timeout = Some(ms);
+        }
+        Ok(())
+    }
+
+    /// Returns the requested timeout a timeout header sets
+    fn timeout_mut(&mut self, name: &str) -> Option<&mut Option<u64>> {
+        if name.eq_ignore_ascii_case(Self::CONNECT_TIMEOUT_HEADER) {
+            Some(&mut self.timeouts.connect_ms)
+        } else if name.eq_ignore_ascii_case(Self::HANDSHAKE_TIMEOUT_HEADER) {
+            Some(&mut self.timeouts.handshake_ms)
+        } else if name.eq_ignore_ascii_case(Self::CONNECT_REPLY_TIMEOUT_HEADER) {
+            Some(&mut self.timeouts.connect_reply_ms)
+        } else if name.eq_ignore_ascii_case(Self::FIRST_BYTE_TIMEOUT_HEADER) {
+            Some(&mut self.timeouts.first_byte_ms)
+        } else {
+            None
+        }
+    }
+
+    /// Reads `country-xx` and `city-name` tokens from a client proxy username
+    ///
+    /// Returns true if any token was found. Header hints take precedence.
+    pub fn apply_username_tokens(&mut self, username: &str) -> bool {
+        let tokens: Vec<&str> = username.split('-').collect();
+        let mut found = false;
+
+        for pair in tokens.windows(2) {
+            let value = pair[1];
+            if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                continue;
+            }
+            match pair[0].to_ascii_lowercase().as_str() {
+                "country" => {
+                    found = true;
+                    self.country.get_or_insert_with(|| value.to_ascii_lowercase());
+                }
+                "city" => {
+                    found = true;
+                    self.city.get_or_insert_with(|| value.to_ascii_lowercase());
+                }
+                _ => {}
+            }
+        }
+
+        found
+    }
+
+    /// Returns the pool the request selects from
+    pub fn pool(&self) -> &str {
+        self.pool.as_deref().unwrap_or(DEFAULT_POOL)
+    }
+
+    /// Returns true if the request names a country or city
+    pub fn has_location(&self) -> bool {
+        self.country.is_some() || self.city.is_some()
+    }
+
+    /// Returns a copy without the requested country and city
+    pub fn without_location(&self) -> Self {
+        Self {
+            country: None,
+            city: None,
+            ..self.clone()
+        }
+    }
+
+    /// Describes the requested location, e.g. `country=de city=berlin`
+    pub fn location(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(country) = &self.country {
+            parts.push(format!("country={country}"));
+        }
+        if let Some(city) = &self.city {
+            parts.push(format!("city={city}"));
+        }
+        parts.join(" ")
+    }
+
+    /// Returns true if a backend belongs to the requested pool and can serve the requested location
+    ///
+    /// Backends whose username template takes the country (or city) match any value,
+    /// since the provider picks the exit location per request.
+    pub fn matches(&self, metadata: &ProxyMetadata) -> bool {
+        if metadata.pool != self.pool() {
+            return false;
+        }
+
+        let template = metadata.username_template.as_ref();
+
+        let country_ok = self.country.as_ref().is_none_or(|wanted| {
+            template.is_some_and(UsernameTemplate::targets_country)
+                || metadata.country().as_deref() == Some(wanted.as_str())
+        });
+        let city_ok = self.city.as_ref().is_none_or(|wanted| {
+            template.is_some_and(UsernameTemplate::targets_city)
+                || metadata.city().is_some_and(|city| same_city(&city, wanted))
+        });
+
+        country_ok && city_ok
+    }
+}
+
+/// Compares city names ignoring case and treating `_` as a space (`new_york` = `New York`)
+fn same_city(a: &str, b: &str) -> bool {
+    let normalize = |s: &str| s.to_lowercase().replace('_', " ");
+    normalize(a) == normalize(b)
+}
+
+impl UsernameTemplate {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut chars = source.chars().peekable();
+        let parts = parse_parts(&mut chars, false)?;
+        if !parts.iter().any(|part| contains_field(part, Field::Username)) {
+            bail!("username template {source:?} does not contain {{username}}");
+        }
+
+        Ok(Self {
+            source: source.into(),
+            parts: parts.into(),
+        })
+    }
+
+    /// Returns true if the template requests a session ID
+    pub fn uses_session(&self) -> bool {
+        self.uses(Field::Session)
+    }
+
+    /// Returns true if the provider picks the exit country from the username
+    pub fn targets_country(&self) -> bool {
+        self.uses(Field::Country)
+    }
+
+    /// Returns true if the provider picks the exit city from the username
+    pub fn targets_city(&self) -> bool {
+        self.uses(Field::City)
+    }
+
+    fn uses(&self, field: Field) -> bool {
+        self.parts.iter().any(|part| contains_field(part, field))
+    }
+
+    /// Expands the template for one request; a missing session hint gets a fresh random ID
+    pub fn expand(&self, username: &str, hints: &SessionHints) -> String {
+        let generated = (hints.session.is_none() && self.uses_session()).then(random_session_id);
+        let lifetime = hints.lifetime.map(|minutes| minutes.to_string());
+
+        let lookup = |field: Field| -> Option<&str> {
+            match field {
+                Field::Username => Some(username),
+                Field::Country => hints.country.as_deref(),
+                Field::City => hints.city.as_deref(),
+                Field::Session => hints.session.as_deref().or(generated.as_deref()),
+                Field::Lifetime => lifetime.as_deref(),
+            }
+        };
+
+        let mut out = String::with_capacity(self.source.len() + username.len());
+        for part in self.parts.iter() {
+            render(part, &lookup, &mut out);
+        }
+        out
+    }
+}
+
+impl TryFrom<String> for UsernameTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        Self::parse(&source)
+    }
+}
+
+impl fmt::Display for UsernameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn parse_parts(
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    in_group: bool,
+) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                flush_literal(&mut literal, &mut parts);
+                parts.push(Part::Field(Field::parse(&name)?));
+            }
+            '[' => {
+                if in_group {
+                    bail!("nested [...] groups are not supported");
+                }
+                flush_literal(&mut literal, &mut parts);
+                parts.push(Part::Group(parse_parts(chars, true)?));
+            }
+            ']' if in_group => {
+                flush_literal(&mut literal, &mut parts);
+                return Ok(parts);
+            }
+            ']' | '}' => bail!("unbalanced '{c}' in username template"),
+            c => literal.push(c),
+        }
+    }
+
+    if in_group {
+        bail!("unclosed '[' in username template");
+    }
+    flush_literal(&mut literal, &mut parts);
+    Ok(parts)
+}
+
+fn flush_literal(literal: &mut String, parts: &mut Vec<Part>) {
+    if !literal.is_empty() {
+        parts.push(Part::Literal(std::mem::take(literal)));
+    }
+}
+
+fn contains_field(part: &Part, field: Field) -> bool {
+    match part {
+        Part::Literal(_) => false,
+        Part::Field(f) => *f == field,
+        Part::Group(parts) => parts.iter().any(|p| contains_field(p, field)),
+    }
+}
+
+fn render<'a>(part: &Part, lookup: &impl Fn(Field) -> Option<&'a str>, out: &mut String) {
+    match part {
+        Part::Literal(text) => out.push_str(text),
+        Part::Field(field) => out.push_str(lookup(*field).unwrap_or_default()),
+        Part::Group(parts) => {
+            let complete = parts.iter().all(|p| match p {
+                Part::Field(field) => lookup(*field).is_some(),
+                _ => true,
+            });
+            if complete {
+                for p in parts {
+                    render(p, lookup, out);
+                }
+            }
+        }
+    }
+}
+
+/// Generates a session ID that is unique per call, for rotating to a new exit IP
+fn random_session_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str =
+        "customer-{username}[-cc-{country}][-sessid-{session}][-sesstime-{lifetime}]";
+
+    fn hints(headers: &[(&str, &str)]) -> SessionHints {
+        let mut hints = SessionHints::default();
+        for (name, value) in headers {
+            hints.set(name, value).unwrap();
+        }
+        hints
+    }
+
+    #[test]
+    fn expands_present_groups_only() {
+        let template = UsernameTemplate::parse(TEMPLATE).unwrap();
+        let hints = hints(&[
+            ("X-Proxywar-Country", "DE"),
+            ("X-Proxywar-Session", "abc_1"),
+            ("X-Proxywar-Session-Lifetime", "10"),
+        ]);
+        assert_eq!(
+            template.expand("bob", &hints),
+            "customer-bob-cc-de-sessid-abc_1-sesstime-10"
+        );
+
+        let country_only = SessionHints {
+            country: Some("us".to_string()),
+            ..Default::default()
+        };
+        let template =
+            UsernameTemplate::parse("customer-{username}[-cc-{country}][-city-{city}]").unwrap();
+        assert_eq!(template.expand("bob", &country_only), "customer-bob-cc-us");
+        assert_eq!(
+            template.expand("bob", &SessionHints::default()),
+            "customer-bob"
+        );
+    }
+
+    #[test]
+    fn generates_a_fresh_session_when_missing() {
+        let template = UsernameTemplate::parse("{username}[-session-{session}]").unwrap();
+        let first = template.expand("bob", &SessionHints::default());
+        let second = template.expand("bob", &SessionHints::default());
+        assert!(first.starts_with("bob-session-"), "{first}");
+        assert_eq!(first.len(), "bob-session-".len() + 16);
+        assert_ne!(first, second);
+
+        // Templates without {session} never get one
+        let template = UsernameTemplate::parse("{username}[-cc-{country}]").unwrap();
+        assert_eq!(template.expand("bob", &SessionHints::default()), "bob");
+    }
+
+    #[test]
+    fn reports_targeted_fields() {
+        let template = UsernameTemplate::parse(TEMPLATE).unwrap();
+        assert!(template.uses_session());
+        assert!(template.targets_country());
+        assert!(!template.targets_city());
+        assert_eq!(template.to_string(), TEMPLATE);
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for source in [
+            "customer-{country}",
+            "{username}-{region}",
+            "{username}[-cc-{country}",
+            "{username}]",
+            "{username}[-a[-{country}]]",
+        ] {
+            assert!(UsernameTemplate::parse(source).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn rejects_hint_values_that_could_inject_parameters() {
+        let mut hints = SessionHints::default();
+        assert!(hints.set("X-Proxywar-Country", "de-sessid-1").is_err());
+        assert!(hints.set("X-Proxywar-Session", "").is_err());
+        assert!(hints.set("X-Proxywar-Session-Lifetime", "ten").is_err());
+        assert!(hints.country.is_none() && hints.session.is_none());
+        assert!(SessionHints::is_hint_header("x-proxywar-session"));
+        assert!(!SessionHints::is_hint_header("x-forwarded-for"));
+    }
+
+    #[test]
+    fn reads_username_tokens_without_overriding_headers() {
+        let mut hints = hints(&[("X-Proxywar-City", "Paris")]);
+        assert!(hints.apply_username_tokens("client-country-FR-city-lyon"));
+        assert_eq!(hints.country.as_deref(), Some("fr"));
+        assert_eq!(hints.city.as_deref(), Some("paris"));
+
+        let mut hints = SessionHints::default();
+        assert!(!hints.apply_username_tokens("plain-user"));
+    }
+}